@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use proxify::proxify_data::ProxifyData;
+use proxify::proxify_frame::FrameReader;
+
+/* Feed arbitrary bytes both straight to the decoder and through the frame
+   reader the daemon uses, neither may panic */
+fuzz_target!(|data: &[u8]| {
+    let _ = ProxifyData::unmarshal_bytes(data);
+
+    let mut frames = FrameReader::new();
+    frames.push(data);
+    while let Ok(Some(frame)) = frames.next_frame() {
+        let _ = ProxifyData::unmarshal_bytes(&frame);
+    }
+});
@@ -24,9 +24,6 @@ pub fn validate_port(port: u16) -> bool{
     true
 }
 
-pub fn validate_ip_address(ip_addr: &String) -> bool {
-    match IpAddr::from_str(ip_addr.as_str()) {
-        Ok(_) => return true,
-        Err(_) => return false
-    }
+pub fn validate_ip_address(ip_addr: &str) -> bool {
+    IpAddr::from_str(ip_addr).is_ok()
 }
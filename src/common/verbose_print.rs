@@ -17,8 +17,6 @@ Spam("This text will be printed  only for VerbosityLevel::Spam");
 */
 
 use std::fmt;
-use std::sync::Mutex;
-use once_cell::sync::Lazy;
 
 
 #[derive(Debug, PartialEq, PartialOrd)]
@@ -54,6 +52,12 @@ impl Verbosity {
     }
 }
 
+impl Default for Verbosity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl fmt::Display for Verbosity {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self.level)
@@ -1,38 +1,125 @@
-use std::thread;
 use std::string::String;
-use std::sync::{Arc, Mutex, Condvar};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::result::Result;
-use std::io::{Read, Write};
-use std::net::{IpAddr, TcpListener, TcpStream, Shutdown};
-use std::time::Duration;
-use std::collections::VecDeque;
-use std::str::FromStr;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, VecDeque};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
 
 use crate::common::VERBOSITY;
 use crate::common::verbose_print::VerbosityLevel;
 use crate::{Error, Warn, Inform, Detail, Spam};
 use crate::common::utils::encode_hex;
-use crate::proxify_config::ProxifyConfig;
-use crate::proxy_conn::ProxyConn;
-use crate::proxy_conn::ProxyConnProtocol;
-use crate::proxify_data::{ProxifyCommand, ProxifyDataType, ProxifyData};
+use crate::proxify_config::{ProxifyConfig, ProxyEntry};
+use crate::proxy_conn::{HealthCheck, ProxyConn, ProxyConnError, ProxyConnMethod, ProxyConnProtocol, ProxyConnResponse};
+use crate::proxify_data::{ProxifyCommand, ProxifyDataType, ProxifyData, ProxifyResponse, ProxifyStatus};
+use crate::proxify_frame::{FrameReader, MAGIC_BYTES, MAX_FRAME_LEN, frame_bytes};
+use crate::selection_strategy::{SelectionStrategy, strategy_by_name};
+use crate::stats_report::{DaemonCounters, StatsReport};
+use crate::admin_api::AdminApi;
+use crate::metrics::MetricsServer;
+use crate::transfer_driver::TransferDriver;
 
 /* To clarify the following type alias:
    A ref-counted thread-safe double-edge list containing ref-counted
    thread-safe elements */
-type ThreadSafeList = Arc<Mutex<VecDeque<Arc<Mutex<ProxyConn>>>>>;
+pub(crate) type ThreadSafeList = Arc<Mutex<VecDeque<Arc<Mutex<ProxyConn>>>>>;
+
+/* The sessions of one connection, shared by its request tasks. A session
+   is locked for the whole of a request, across awaits, so it uses the tokio
+   mutex. */
+type SessionMap = Arc<Mutex<HashMap<u8, Arc<tokio::sync::Mutex<ProxifySession>>>>>;
+
+/* The write half of a client connection, shared by its request tasks */
+type SharedWriter = Arc<tokio::sync::Mutex<OwnedWriteHalf>>;
+
+/* How often connections wake up to expire sessions and notice the daemon
+   exiting */
+const CONNECTION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/* How often a prepare task looks again when the ready pool is full, i.e.
+   how quickly a consumed proxy is replaced */
+const PREPARE_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
-static MAGIC_BYTES: [u8; 4] = [ 0xAB, 0xBA, 0xAB, 0xBA ];
+/* The session byte of requests that do not belong to any session */
+const NO_SESSION: u8 = 0;
+
+/* A client session, i.e. requests with the same non-zero session byte on one
+   connection. The session keeps the same proxy (and so the same exit IP) and
+   its cookies across requests until END_SESSION, the session being idle for
+   too long or the client disconnecting. */
+struct ProxifySession {
+    proxy: Option<Arc<Mutex<ProxyConn>>>,
+    cookies: Vec<String>,
+    last_used: Instant,
+}
+
+impl ProxifySession {
+    fn new() -> Self {
+        ProxifySession {
+            proxy: None,
+            cookies: Vec::new(),
+            last_used: Instant::now(),
+        }
+    }
+}
+
+/* How failed health checks are retried */
+#[derive(Clone, Copy)]
+pub struct BackoffPolicy {
+    base: Duration,
+    max: Duration,
+    /* Zero never quarantines */
+    max_failures: u32,
+}
+
+impl BackoffPolicy {
+    /* base, doubled for every failure in a row after the first */
+    fn delay(&self, consecutive_failures: u32) -> Duration {
+        let factor = 1_u32.checked_shl(consecutive_failures.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+
+    fn should_quarantine(&self, consecutive_failures: u32) -> bool {
+        self.max_failures > 0 && consecutive_failures >= self.max_failures
+    }
+}
 
 pub struct ProxifyDaemon {
     bind_addr: String,
     bind_port: u16,
     nr_of_proxies: u8,
-    nr_of_prepare_threads: u8,
+    /* Number of health checks running at the same time */
+    nr_of_prepare_tasks: u8,
+    session_timeout: Duration,
+    /* Seconds a client request may take, connecting included */
+    request_timeout: u16,
+    max_connections: usize,
+    drain_timeout: Duration,
+    /* Zero disables re-checking */
+    recheck_interval: Duration,
+    backoff: BackoffPolicy,
+    health_check: Arc<HealthCheck>,
+    /* How ready proxies are picked unless a request asks otherwise */
+    strategy: &'static dyn SelectionStrategy,
     notready_proxies: ThreadSafeList,
     ready_proxies: ThreadSafeList,
     inuse_proxies: ThreadSafeList,
+    /* Proxies that failed too often, never used again */
+    quarantined_proxies: ThreadSafeList,
+    counters: Arc<DaemonCounters>,
+    /* 0 if the admin API is not started */
+    admin_port: u16,
+    /* 0 if the metrics endpoint is not started */
+    metrics_port: u16,
+    /* Number of proxies from the configuration, proxies added at runtime
+       get the ids after them */
+    nr_configured_proxies: u16,
 }
 
 /* Destructor */
@@ -48,18 +135,10 @@ impl ProxifyDaemon {
 
         let mut id = 0_u16;
         for p in config.proxies_list {
-            let prot: ProxyConnProtocol = match p.0.parse() {
-                Ok(p) => p,
-                Err(e) => return Err(format!("Failed to parse protocol {}: {}", p.0, e.to_string())),
-            };
-            proxies_list.push_back(Arc::new(Mutex::new(
-                ProxyConn::new(id,
-                               p.0.parse().unwrap(),
-                               p.1,
-                               p.2,
-                               p.3,
-                               p.4)
-            )));
+            if id == ProxifyResponse::NO_PROXY_ID {
+                return Err(format!("Too many proxies, at most {} are supported", ProxifyResponse::NO_PROXY_ID));
+            }
+            proxies_list.push_back(Arc::new(Mutex::new(Self::new_proxy(id, p)?)));
             id += 1;
         }
 
@@ -69,13 +148,44 @@ impl ProxifyDaemon {
             bind_addr: config.bind_addr,
             bind_port: config.bind_port,
             nr_of_proxies: config.nr_of_proxies,
-            nr_of_prepare_threads: config.nr_of_prepare_threads,
+            nr_of_prepare_tasks: config.nr_of_prepare_threads,
+            session_timeout: Duration::from_secs(config.session_timeout.into()),
+            request_timeout: config.request_timeout,
+            max_connections: config.max_connections as usize,
+            drain_timeout: Duration::from_secs(config.drain_timeout.into()),
+            recheck_interval: Duration::from_secs(config.recheck_interval.into()),
+            backoff: BackoffPolicy {
+                base: Duration::from_secs(config.backoff_base.into()),
+                max: Duration::from_secs(config.backoff_max.into()),
+                max_failures: config.max_failures,
+            },
+            health_check: Arc::new(config.health_check),
+            strategy: config.selection_strategy,
             notready_proxies: Arc::new(Mutex::new(proxies_list)),
             ready_proxies: Arc::new(Mutex::new(VecDeque::new())),
             inuse_proxies: Arc::new(Mutex::new(VecDeque::new())),
+            quarantined_proxies: Arc::new(Mutex::new(VecDeque::new())),
+            counters: Arc::new(DaemonCounters::new()),
+            admin_port: config.admin_port,
+            metrics_port: config.metrics_port,
+            nr_configured_proxies: id,
         })
     }
 
+    /* Create a proxy from an entry of the proxies file */
+    pub fn new_proxy(id: u16, p: ProxyEntry) -> Result<ProxyConn, String> {
+        let prot: ProxyConnProtocol = match p.0.parse() {
+            Ok(p) => p,
+            Err(e) => return Err(format!("Failed to parse protocol {}: {}", p.0, e)),
+        };
+        Ok(ProxyConn::new(id,
+                          prot,
+                          p.1,
+                          p.2,
+                          p.3,
+                          p.4))
+    }
+
     pub fn get_bind_addr(&self) -> &String {
         &self.bind_addr
     }
@@ -84,117 +194,319 @@ impl ProxifyDaemon {
         self.bind_port
     }
 
-    pub fn get_ready_proxy(ready_proxies: ThreadSafeList,
+    pub fn get_ready_proxy(strategy: &dyn SelectionStrategy,
+                           ready_proxies: ThreadSafeList,
                            inuse_proxies: ThreadSafeList) -> Option<Arc<Mutex<ProxyConn>>> {
-       let mut r_proxies = ready_proxies.lock().unwrap();
-        if r_proxies.is_empty() {
-            Inform!("No proxies are ready yet.");
-            return None;
-        }
-        let proxy = r_proxies.pop_front().unwrap();
+        let mut r_proxies = ready_proxies.lock().unwrap();
+        let proxy = match strategy.select(&r_proxies).and_then(|i| r_proxies.remove(i)) {
+            Some(p) => p,
+            None => {
+                Inform!("No proxies are ready yet.");
+                return None;
+            },
+        };
+        proxy.lock().unwrap().set_used();
         drop(r_proxies); // Am I dropping the Mutex or the Arc<Mutex<VecDeque<...>>>?
         let mut u_proxies = inuse_proxies.lock().unwrap();
         u_proxies.push_back(proxy.clone());
         Some(proxy)
     }
 
-    /* Run in a separate thread. This thread will run forever with no
-       interaction. It will exit if the argument "exiting" becomes True. */
-    pub fn prepare_proxies(thread_nr: u8,
-                           notready_proxies: ThreadSafeList,
-                           ready_proxies: ThreadSafeList,
-                           exiting: Arc<AtomicBool>) {
-        Detail!("Thread {} is starting to prepare proxies", thread_nr);
+    /* Move a proxy out of the in-use list once a client is done with it. A
+       working proxy goes straight back to ready_proxies unless nr_of_proxies
+       are ready already, then it is parked with the not ready ones. A
+       failing one is marked unprepared and handed to the prepare tasks
+       again. One that is due for a re-check, or that failed most of its
+       recent transfers, is handed to them first in line. */
+    pub fn release_proxy(proxy: Arc<Mutex<ProxyConn>>,
+                         working: bool,
+                         nr_of_proxies: usize,
+                         notready_proxies: ThreadSafeList,
+                         ready_proxies: ThreadSafeList,
+                         inuse_proxies: ThreadSafeList) {
+        /* Never hold the proxy lock while taking a list lock, keep the
+           proxy locked only for as long as needed */
+        let (id, recheck_due, disabled) = {
+            let mut p = proxy.lock().unwrap();
+            if !working {
+                p.set_unprepared();
+            }
+            (p.get_id(), p.is_recheck_due() || p.get_stats().is_failing(), p.is_disabled())
+        };
+
+        let mut u_proxies = inuse_proxies.lock().unwrap();
+        u_proxies.retain(|p| !Arc::ptr_eq(p, &proxy));
+        drop(u_proxies);
+
+        if disabled {
+            Detail!("Proxy {} released, it is disabled", id);
+            notready_proxies.lock().unwrap().push_back(proxy);
+        } else if working && recheck_due {
+            Detail!("Proxy {} released, re-checking it first", id);
+            notready_proxies.lock().unwrap().push_front(proxy);
+        } else if working {
+            /* The prepare tasks replaced it while it was in use, keeping it
+               ready too would grow the pool to the peak number of clients */
+            let mut r_proxies = ready_proxies.lock().unwrap();
+            if r_proxies.len() < nr_of_proxies {
+                Spam!("Proxy {} released back to the ready proxies", id);
+                r_proxies.push_back(proxy);
+            } else {
+                drop(r_proxies);
+                Spam!("Proxy {} released, enough are ready, parking it", id);
+                notready_proxies.lock().unwrap().push_back(proxy);
+            }
+        } else {
+            Detail!("Proxy {} failed, moving it to the not ready proxies", id);
+            notready_proxies.lock().unwrap().push_back(proxy);
+        }
+    }
+
+    /* Run as a task on the runtime until the argument "exiting" becomes
+       True. Each task checks one proxy at a time, the checks themselves run
+       on the transfer driver. Together they keep nr_of_proxies proxies in
+       ready_proxies, preparing is the number of checks in progress. */
+    #[allow(clippy::too_many_arguments)]
+    pub async fn prepare_proxies(task_nr: u8,
+                                 driver: Arc<TransferDriver>,
+                                 backoff: BackoffPolicy,
+                                 health_check: Arc<HealthCheck>,
+                                 nr_of_proxies: usize,
+                                 preparing: Arc<AtomicUsize>,
+                                 notready_proxies: ThreadSafeList,
+                                 ready_proxies: ThreadSafeList,
+                                 quarantined_proxies: ThreadSafeList,
+                                 counters: Arc<DaemonCounters>,
+                                 exiting: Arc<AtomicBool>) {
+        Detail!("Task {} is starting to prepare proxies", task_nr);
         while !exiting.load(Ordering::Relaxed) {
             /* Process flow:
-               (TODO: Loop inuse and try_lock, if success then push_back to unused)
-               then if nr_proxies not reached pop_first from notready, make
-               ready then push_back to ready_proxies */
-            let mut notready_guard = notready_proxies.lock().unwrap();
-            if notready_guard.is_empty() {
-                Spam!("[prepare thread {}] No proxies to prepare, checking again in 1 second", thread_nr);
-                drop(notready_guard);
-                thread::sleep(Duration::from_secs(1));
+               if nr_proxies not reached take the first proxy from notready
+               that is not backing off, make ready then push_back to
+               ready_proxies. In-use proxies are returned by the connection
+               tasks via release_proxy(). Claim a slot first so that the
+               tasks together do not overshoot. */
+            let in_progress = preparing.fetch_add(1, Ordering::SeqCst) + 1;
+            if ready_proxies.lock().unwrap().len() + in_progress > nr_of_proxies {
+                preparing.fetch_sub(1, Ordering::SeqCst);
+                tokio::time::sleep(PREPARE_POLL_INTERVAL).await;
                 continue;
             }
 
-            let proxy_guard = notready_guard.pop_front().unwrap();
-            drop(notready_guard);
+            let proxy = Self::next_to_prepare(&notready_proxies);
+            let proxy = match proxy {
+                Some(p) => p,
+                None => {
+                    preparing.fetch_sub(1, Ordering::SeqCst);
+                    Spam!("[prepare task {}] No proxies due for preparing, checking again in 1 second", task_nr);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                },
+            };
 
-            let mut proxy = proxy_guard.lock().unwrap();
-            if let Err(e) = proxy.prepare() {
-                Error!("[prepare thread {}] Failed to prepare proxy {}: {}", thread_nr, proxy.get_id(), e.to_string());
+            let result = ProxyConn::prepare_async(&proxy, &driver, &health_check).await;
+            let (id, prepared, failures, disabled) = {
+                let mut p = proxy.lock().unwrap();
+                if !p.is_prepared() {
+                    let failures = p.get_consecutive_failures().saturating_add(1);
+                    if backoff.should_quarantine(failures) {
+                        p.set_quarantined();
+                    }
+                    p.record_failure(backoff.delay(failures));
+                }
+                (p.get_id(), p.is_prepared(), p.get_consecutive_failures(), p.is_disabled())
+            };
+            counters.record_prepare(prepared);
+            if let Err(e) = result {
+                Error!("[prepare task {}] Failed to prepare proxy {}: {}", task_nr, id, e);
             }
 
-            /* If it is prepared, add it to ready_proxies
-               else push_back to notready_proxies */
-            if proxy.is_prepared() {
-                Spam!("[prepare thread {}] Proxy {} is now prepared", thread_nr, proxy.get_id());
-                /* Intentionally not handling the error since it should never
-                   happen */
-                let mut ready_guard = ready_proxies.lock().unwrap();
-                drop(proxy);
-                ready_guard.push_back(proxy_guard);
+            /* If it is prepared, add it to ready_proxies, if it failed too
+               often quarantine it, else push_back to notready_proxies. One
+               disabled while being checked waits in notready_proxies. */
+            if disabled && !backoff.should_quarantine(failures) {
+                Detail!("[prepare task {}] Proxy {} was disabled while being prepared", task_nr, id);
+                notready_proxies.lock().unwrap().push_back(proxy);
+            } else if prepared {
+                Spam!("[prepare task {}] Proxy {} is now prepared", task_nr, id);
+                ready_proxies.lock().unwrap().push_back(proxy);
+            } else if backoff.should_quarantine(failures) {
+                Warn!("[prepare task {}] Proxy {} failed {} times in a row, quarantining it", task_nr, id, failures);
+                quarantined_proxies.lock().unwrap().push_back(proxy);
             } else {
-                Spam!("[prepare thread {}] Proxy {} failed to prepare", thread_nr, proxy.get_id());
-                drop(proxy);
-                let mut notready_guard = notready_proxies.lock().unwrap();
-                notready_guard.push_back(proxy_guard);
+                Spam!("[prepare task {}] Proxy {} failed to prepare ({} in a row), retrying in {} seconds",
+                      task_nr, id, failures, backoff.delay(failures).as_secs());
+                notready_proxies.lock().unwrap().push_back(proxy);
+            }
+            preparing.fetch_sub(1, Ordering::SeqCst);
+        }
+        Spam!("Task {} is exiting", task_nr);
+    }
+
+    /* Take the first not ready proxy whose backoff has passed and that is
+       not disabled */
+    fn next_to_prepare(notready_proxies: &ThreadSafeList) -> Option<Arc<Mutex<ProxyConn>>> {
+        let mut n_proxies = notready_proxies.lock().unwrap();
+        let pos = n_proxies.iter().position(|p| {
+            let p = p.lock().unwrap();
+            p.is_retry_due() && !p.is_disabled()
+        })?;
+        n_proxies.remove(pos)
+    }
+
+    /* Run as a task on the runtime until the argument "exiting" becomes
+       True. Ready proxies that have not worked for recheck_interval are put
+       first in line for the prepare tasks, in-use ones are flagged so that
+       release_proxy() does the same when they come back. */
+    pub async fn recheck_proxies(recheck_interval: Duration,
+                                 notready_proxies: ThreadSafeList,
+                                 ready_proxies: ThreadSafeList,
+                                 inuse_proxies: ThreadSafeList,
+                                 exiting: Arc<AtomicBool>) {
+        Detail!("Re-checking proxies every {} seconds", recheck_interval.as_secs());
+        while !exiting.load(Ordering::Relaxed) {
+            tokio::time::sleep(CONNECTION_POLL_INTERVAL).await;
+
+            /* Locking proxies while holding a list lock is fine, the other
+               way around is not */
+            let stale: VecDeque<Arc<Mutex<ProxyConn>>> = {
+                let mut r_proxies = ready_proxies.lock().unwrap();
+                let (stale, fresh) = r_proxies.drain(..)
+                    .partition(|p| p.lock().unwrap().is_stale(recheck_interval));
+                *r_proxies = fresh;
+                stale
+            };
+            if !stale.is_empty() {
+                Detail!("Re-checking {} ready proxies", stale.len());
+                let mut n_proxies = notready_proxies.lock().unwrap();
+                for p in stale.into_iter().rev() {
+                    n_proxies.push_front(p);
+                }
+            }
+
+            for p in inuse_proxies.lock().unwrap().iter() {
+                let mut p = p.lock().unwrap();
+                if !p.is_recheck_due() && p.is_stale(recheck_interval) {
+                    Spam!("Proxy {} is in use and due for a re-check", p.get_id());
+                    p.set_recheck_due();
+                }
             }
         }
-        Spam!("Thread {} is exiting", thread_nr);
     }
 
     pub fn start(&mut self, exiting: &Arc<AtomicBool>) -> std::io::Result<()>{
-        let listener = TcpListener::bind((self.bind_addr.as_str(), self.bind_port)).unwrap();
-        let nr_threads: Arc<Mutex<i32>> = Arc::new(Mutex::new(0));
-        let mut prepare_threads: Vec<thread::JoinHandle<_>> = Vec::new();
-
-        /* Kick off a given number threads that will keep proxies prepared */
-        Detail!("Preparing {} number of proxies using {} threads", self.nr_of_proxies, self.nr_of_prepare_threads);
-        for thread_nr in 1..=self.nr_of_prepare_threads {
-            let exiting_clone = exiting.clone();
-            let notready_proxies_clone = self.notready_proxies.clone();
-            let ready_proxies_clone = self.ready_proxies.clone();
-            Spam!("Starting prepare thread {}", thread_nr);
-            prepare_threads.push(thread::spawn(move || {
-                Self::prepare_proxies(thread_nr,
-                                      notready_proxies_clone,
-                                      ready_proxies_clone,
-                                      exiting_clone);
-                })
-            )
+        /* Every transfer, health checks and client requests alike, runs on
+           the driver's thread. Connections and health checks are tasks on
+           the runtime's worker threads. */
+        let driver = Arc::new(TransferDriver::new()?);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let result = runtime.block_on(self.serve(exiting, driver));
+        /* Everything has been joined or aborted by serve() already */
+        runtime.shutdown_timeout(Duration::from_secs(1));
+        result
+    }
+
+    /* The accept loop, a task is spawned for every client connection. Once
+       "exiting" is set it stops accepting and waits for the connections to
+       drain before stopping the prepare tasks. */
+    async fn serve(&self, exiting: &Arc<AtomicBool>, driver: Arc<TransferDriver>) -> std::io::Result<()> {
+        let listener = TcpListener::bind((self.bind_addr.as_str(), self.bind_port)).await?;
+        let admin_listener = match self.admin_port {
+            0 => None,
+            port => Some(TcpListener::bind((self.bind_addr.as_str(), port)).await?),
+        };
+        let metrics_listener = match self.metrics_port {
+            0 => None,
+            port => Some(TcpListener::bind((self.bind_addr.as_str(), port)).await?),
+        };
+        let nr_connections: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+        let mut prepare_tasks: JoinSet<()> = JoinSet::new();
+        let mut connections: JoinSet<()> = JoinSet::new();
+
+        if let Some(admin_listener) = admin_listener {
+            let admin = AdminApi::new(self.notready_proxies.clone(),
+                                      self.ready_proxies.clone(),
+                                      self.inuse_proxies.clone(),
+                                      self.quarantined_proxies.clone(),
+                                      nr_connections.clone(),
+                                      self.counters.clone(),
+                                      self.nr_configured_proxies);
+            prepare_tasks.spawn(admin.serve(admin_listener, exiting.clone()));
+        }
+        if let Some(metrics_listener) = metrics_listener {
+            let metrics = MetricsServer::new(self.notready_proxies.clone(),
+                                             self.ready_proxies.clone(),
+                                             self.inuse_proxies.clone(),
+                                             self.quarantined_proxies.clone(),
+                                             nr_connections.clone(),
+                                             self.counters.clone());
+            prepare_tasks.spawn(metrics.serve(metrics_listener, exiting.clone()));
         }
 
-        /* More on a proper implementation of TcpListener::incoming():
-           https://stackoverflow.com/questions/56692961/graceful-exit-tcplistener-incoming */
-        for stream in listener.incoming() {
-            if exiting.load(Ordering::Relaxed) {
-                /* If the application is exiting break the loop */
-                break;
-            }
-            match stream {
-                Ok(stream) => {
-                    if *nr_threads.lock().unwrap() >= 50 {
-                        Inform!("Too many threads running, ignoring connections for 1 second");
-                        thread::sleep(Duration::from_secs(1));
-                        continue;
+        /* Kick off a given number tasks that will keep proxies prepared */
+        Detail!("Preparing {} number of proxies using {} tasks", self.nr_of_proxies, self.nr_of_prepare_tasks);
+        let preparing: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+        for task_nr in 1..=self.nr_of_prepare_tasks {
+            Spam!("Starting prepare task {}", task_nr);
+            prepare_tasks.spawn(Self::prepare_proxies(task_nr,
+                                                      driver.clone(),
+                                                      self.backoff,
+                                                      self.health_check.clone(),
+                                                      self.nr_of_proxies.into(),
+                                                      preparing.clone(),
+                                                      self.notready_proxies.clone(),
+                                                      self.ready_proxies.clone(),
+                                                      self.quarantined_proxies.clone(),
+                                                      self.counters.clone(),
+                                                      exiting.clone()));
+        }
+        if !self.recheck_interval.is_zero() {
+            prepare_tasks.spawn(Self::recheck_proxies(self.recheck_interval,
+                                                      self.notready_proxies.clone(),
+                                                      self.ready_proxies.clone(),
+                                                      self.inuse_proxies.clone(),
+                                                      exiting.clone()));
+        } else {
+            Inform!("Re-checking proxies is disabled");
+        }
+
+        while !exiting.load(Ordering::Relaxed) {
+            while connections.try_join_next().is_some() {}
+
+            /* Wake up regularly to notice the daemon exiting */
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = tokio::time::sleep(CONNECTION_POLL_INTERVAL) => continue,
+            };
+            match accepted {
+                Ok((stream, peer_addr)) => {
+                    /* Over the limit the client still gets the handshake and
+                       then BUSY, so it knows to back off instead of seeing a
+                       reset */
+                    let busy = nr_connections.load(Ordering::Relaxed) >= self.max_connections;
+                    if busy {
+                        Inform!("Too many connections, answering {} with BUSY", peer_addr);
+                    } else {
+                        Inform!("Accepted connection from address {}", peer_addr);
+                        nr_connections.fetch_add(1, Ordering::Relaxed);
                     }
-                    Inform!("Accepted connection from address {}", stream.peer_addr().unwrap());
-                    let exiting_clone = exiting.clone();
-                    let nr_threads_clone = nr_threads.clone();
-                    let notready_proxies_clone = self.notready_proxies.clone();
-                    let ready_proxies_clone = self.ready_proxies.clone();
-                    let inuse_proxies_clone = self.inuse_proxies.clone();
-                    *nr_threads.lock().unwrap() += 1;
-                    thread::spawn(move|| {
-                        Self::handle_accept(stream,
-                                            exiting_clone,
-                                            nr_threads_clone,
-                                            notready_proxies_clone,
-                                            ready_proxies_clone,
-                                            inuse_proxies_clone)
-                    });
+                    connections.spawn(Self::handle_accept(stream,
+                                                          busy,
+                                                          exiting.clone(),
+                                                          nr_connections.clone(),
+                                                          self.session_timeout,
+                                                          self.request_timeout,
+                                                          self.drain_timeout,
+                                                          driver.clone(),
+                                                          self.strategy,
+                                                          self.nr_of_proxies.into(),
+                                                          self.notready_proxies.clone(),
+                                                          self.ready_proxies.clone(),
+                                                          self.inuse_proxies.clone(),
+                                                          self.quarantined_proxies.clone(),
+                                                          self.counters.clone()));
                 }
                 Err(e) => {
                     Error!("Failed to accept incoming connection: {}", e);
@@ -202,16 +514,30 @@ impl ProxifyDaemon {
             }
         }
 
-        /* Wait for all prepare threads to finish */
-        let mut threads_left = self.nr_of_prepare_threads;
-        for pt in prepare_threads {
-            Spam!("Waiting for {} prepare thread(s) to join", threads_left);
-            pt.join().unwrap();
-            threads_left -= 1;
+        drop(listener);
+
+        /* The connections notice "exiting" within a poll interval and then
+           get drain_timeout for their requests, plus some time to say bye */
+        Inform!("Waiting for {} connection(s) to drain", connections.len());
+        let deadline = tokio::time::Instant::now() + self.drain_timeout + 2 * CONNECTION_POLL_INTERVAL;
+        if tokio::time::timeout_at(deadline, Self::join_all(&mut connections)).await.is_err() {
+            Warn!("Closing {} connection(s) that did not drain in time", connections.len());
+            connections.shutdown().await;
+        }
+
+        /* Wait for all prepare tasks to finish their current check */
+        Spam!("Waiting for {} prepare task(s) to join", prepare_tasks.len());
+        if tokio::time::timeout(self.drain_timeout, Self::join_all(&mut prepare_tasks)).await.is_err() {
+            Warn!("Aborting {} prepare task(s)", prepare_tasks.len());
+            prepare_tasks.shutdown().await;
         }
         Ok(())
     }
 
+    async fn join_all(tasks: &mut JoinSet<()>) {
+        while tasks.join_next().await.is_some() {}
+    }
+
     /* A very simple check to ensure the client is compatible */
     fn authenticate(data: &[u8]) -> Result<(), &'static str> {
         Spam!("Magic bytes received: {}", encode_hex(data));
@@ -220,31 +546,388 @@ impl ProxifyDaemon {
         Err("Wrong magic bytes")
     }
 
-    fn handle_accept(mut stream: TcpStream,
-                     exiting: Arc<AtomicBool>,
-                     nr_threads: Arc<Mutex<i32>>,
-                     notready_proxies: ThreadSafeList,
-                     ready_proxies: ThreadSafeList,
-                     inuse_proxies: ThreadSafeList,
-                     ) {
+    /* Pick a ready proxy and use it to perform the request described by the
+       client data. The URL is taken from the first URL TLV, the headers from
+       the HEADER TLVs and the body from the DATA TLVs. For the generic
+       REQUEST command the method is taken from the METHOD TLV. A STRATEGY
+       TLV overrides how the proxy is picked. Within a session the session's
+       proxy and cookies are used. */
+    #[allow(clippy::too_many_arguments)]
+    async fn process_request(parsed_data: &ProxifyData,
+                             session: Option<&mut ProxifySession>,
+                             driver: &TransferDriver,
+                             request_timeout: u16,
+                             strategy: &'static dyn SelectionStrategy,
+                             nr_of_proxies: usize,
+                             notready_proxies: ThreadSafeList,
+                             ready_proxies: ThreadSafeList,
+                             inuse_proxies: ThreadSafeList,
+                             counters: &DaemonCounters) -> ProxifyResponse {
+        let session_id = parsed_data.session;
+
+        let url = match parsed_data.get_url() {
+            Some(u) => u,
+            None => return ProxifyResponse::error(session_id, ProxifyStatus::BAD_REQUEST, None, "No URL given"),
+        };
+
+        let method = match parsed_data.command {
+            ProxifyCommand::REQUEST_POST => ProxyConnMethod::POST,
+            ProxifyCommand::REQUEST => match parsed_data.get_method().map(|m| m.parse::<ProxyConnMethod>()) {
+                Some(Ok(m)) => m,
+                Some(Err(e)) => return ProxifyResponse::error(session_id, ProxifyStatus::BAD_REQUEST, None, &e),
+                None => return ProxifyResponse::error(session_id, ProxifyStatus::BAD_REQUEST, None, "No method given"),
+            },
+            _ => ProxyConnMethod::GET,
+        };
+
+        let strategy = match parsed_data.get_strategy().map(|s| strategy_by_name(&s)) {
+            Some(Ok(s)) => s,
+            Some(Err(e)) => return ProxifyResponse::error(session_id, ProxifyStatus::BAD_REQUEST, None, &e),
+            None => strategy,
+        };
+
+        let pinned_proxy = session.as_ref().and_then(|s| s.proxy.clone());
+        let proxy = match pinned_proxy {
+            Some(p) => p,
+            None => match Self::get_ready_proxy(strategy, ready_proxies.clone(), inuse_proxies.clone()) {
+                Some(p) => p,
+                None => return ProxifyResponse::error(session_id, ProxifyStatus::NO_PROXY_READY, None, "No proxy ready"),
+            },
+        };
+
+        let headers = parsed_data.get_headers();
+        let headers = if headers.is_empty() { None } else { Some(headers) };
+
+        /* POST always sends a body (possibly empty), the other methods only
+           if the client gave one */
+        let body = parsed_data.get_body();
+        let send_data = if method == ProxyConnMethod::POST || !body.is_empty() {
+            Some(body.as_slice())
+        } else {
+            None
+        };
+
+        let mut session = session;
+        let (proxy_id, protocol) = {
+            let p = proxy.lock().unwrap();
+            (p.get_id(), p.get_protocol().to_string())
+        };
+        Detail!("{} '{}' using proxy {}", method, url, proxy_id);
+        let cookie_jar = match session.as_deref_mut() {
+            Some(s) => {
+                s.last_used = Instant::now();
+                Some(&mut s.cookies)
+            },
+            None => None,
+        };
+        let started = Instant::now();
+        let result = ProxyConn::request_async(&proxy,
+                                              driver,
+                                              method,
+                                              &url,
+                                              &headers,
+                                              request_timeout,
+                                              send_data,
+                                              cookie_jar).await;
+        counters.record_upstream_latency(proxy_id, protocol, started.elapsed());
+
+        /* Any HTTP status means the proxy did its job, only errors that
+           point at the proxy count against it. A bad URL or an upstream
+           that can not be reached is the client's problem. A session keeps
+           its proxy for as long as it works, a failed one is replaced on
+           the next request. */
+        let working = match &result {
+            Ok(_) => true,
+            Err(e) => !e.is_proxy_fault(),
+        };
+        match session {
+            Some(s) if working => s.proxy = Some(proxy),
+            Some(s) => {
+                s.proxy = None;
+                Self::release_proxy(proxy, false, nr_of_proxies, notready_proxies, ready_proxies, inuse_proxies);
+            },
+            None => Self::release_proxy(proxy,
+                                        working,
+                                        nr_of_proxies,
+                                        notready_proxies,
+                                        ready_proxies,
+                                        inuse_proxies),
+        }
+
+        match result {
+            Ok(resp) => Self::build_response(session_id, proxy_id, resp),
+            Err(e) => {
+                Error!("{} '{}' using proxy {} failed: {}", method, url, proxy_id, e);
+                let status = match e {
+                    ProxyConnError::Timeout(_) => ProxifyStatus::UPSTREAM_TIMEOUT,
+                    ProxyConnError::Request(_) => ProxifyStatus::BAD_REQUEST,
+                    ProxyConnError::Proxy(_) |
+                    ProxyConnError::Failed(_) => ProxifyStatus::UPSTREAM_ERROR,
+                };
+                ProxifyResponse::error(session_id, status, Some(proxy_id), &e.to_string())
+            },
+        }
+    }
+
+    /* A successful response carries a STATUS TLV followed by one HEADER TLV
+       per response header and the body split over DATA TLVs. One that does
+       not fit in a frame is answered with an error instead, the client
+       would drop the connection over it. */
+    fn build_response(session: u8, proxy_id: u16, resp: ProxyConnResponse) -> ProxifyResponse {
+        let mut response = ProxifyResponse::new(session, ProxifyStatus::OK, Some(proxy_id));
+        response.add_tlv(ProxifyDataType::STATUS, &(resp.status_code as u16).to_be_bytes());
+        for h in resp.headers {
+            response.add_tlv(ProxifyDataType::HEADER, h.as_bytes());
+        }
+        response.add_tlv(ProxifyDataType::DATA, &resp.body);
+
+        if response.marshalled_len() > MAX_FRAME_LEN {
+            Error!("The response through proxy {} is too large ({} bytes)", proxy_id, response.marshalled_len());
+            return ProxifyResponse::error(session,
+                                          ProxifyStatus::UPSTREAM_ERROR,
+                                          Some(proxy_id),
+                                          &format!("The response is larger than {} bytes", MAX_FRAME_LEN));
+        }
+        response
+    }
+
+    /* End a session, releasing its proxy. Returns the id of the proxy the
+       session was using, if any. */
+    fn end_session(session: &mut ProxifySession,
+                   nr_of_proxies: usize,
+                   notready_proxies: ThreadSafeList,
+                   ready_proxies: ThreadSafeList,
+                   inuse_proxies: ThreadSafeList) -> Option<u16> {
+        let proxy = session.proxy.take()?;
+        let proxy_id = proxy.lock().unwrap().get_id();
+        Self::release_proxy(proxy, true, nr_of_proxies, notready_proxies, ready_proxies, inuse_proxies);
+        Some(proxy_id)
+    }
+
+    /* Sessions with a request in flight hold a clone of the session Arc
+       and are never idle, so they are left alone */
+    fn expire_sessions(sessions: &SessionMap,
+                       session_timeout: Duration,
+                       nr_of_proxies: usize,
+                       notready_proxies: &ThreadSafeList,
+                       ready_proxies: &ThreadSafeList,
+                       inuse_proxies: &ThreadSafeList) {
+        let mut sessions = sessions.lock().unwrap();
+        let expired: Vec<u8> = sessions.iter()
+            .filter(|(_, s)| Arc::strong_count(s) == 1)
+            .filter(|(_, s)| s.try_lock().is_ok_and(|s| s.last_used.elapsed() >= session_timeout))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            Detail!("Session {} has been idle for too long, ending it", id);
+            let session = sessions.remove(&id).unwrap();
+            if let Ok(mut session) = session.try_lock() {
+                Self::end_session(&mut session,
+                                  nr_of_proxies,
+                                  notready_proxies.clone(),
+                                  ready_proxies.clone(),
+                                  inuse_proxies.clone());
+            };
+        }
+    }
+
+    async fn send_response(writer: &SharedWriter, response: &ProxifyResponse) -> std::io::Result<()> {
+        writer.lock().await.write_all(&frame_bytes(&response.marshal_bytes())).await
+    }
+
+    /* Run in a task per command so that slow upstreams do not hold up the
+       other requests on the connection. The response is written as soon as
+       it is ready, tagged with the request id of the command. Commands
+       within one session run one at a time and in the order they arrived
+       since they share a proxy and cookies: each waits for the previous
+       command of its session and drops done when finished. */
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_command(parsed_data: ProxifyData,
+                            previous: Option<oneshot::Receiver<()>>,
+                            done: Option<oneshot::Sender<()>>,
+                            writer: SharedWriter,
+                            sessions: SessionMap,
+                            driver: Arc<TransferDriver>,
+                            request_timeout: u16,
+                            strategy: &'static dyn SelectionStrategy,
+                            nr_of_proxies: usize,
+                            notready_proxies: ThreadSafeList,
+                            ready_proxies: ThreadSafeList,
+                            inuse_proxies: ThreadSafeList,
+                            quarantined_proxies: ThreadSafeList,
+                            nr_connections: Arc<AtomicUsize>,
+                            counters: Arc<DaemonCounters>) {
+        let session_id = parsed_data.session;
+        let request_id = parsed_data.request_id;
+
+        if let Some(previous) = previous {
+            let _ = previous.await;
+        }
+
+        let mut response = match parsed_data.command {
+            ProxifyCommand::REQUEST_GET |
+            ProxifyCommand::REQUEST_POST |
+            ProxifyCommand::REQUEST => {
+                Detail!("Processing command {:?} (request {})", parsed_data.command, request_id);
+                if session_id == NO_SESSION {
+                    Self::process_request(&parsed_data,
+                                          None,
+                                          &driver,
+                                          request_timeout,
+                                          strategy,
+                                          nr_of_proxies,
+                                          notready_proxies,
+                                          ready_proxies,
+                                          inuse_proxies,
+                                          &counters).await
+                } else {
+                    let session = sessions.lock().unwrap()
+                        .entry(session_id)
+                        .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(ProxifySession::new())))
+                        .clone();
+                    let mut session_guard = session.lock().await;
+                    let response = Self::process_request(&parsed_data,
+                                                         Some(&mut session_guard),
+                                                         &driver,
+                                                         request_timeout,
+                                                         strategy,
+                                                         nr_of_proxies,
+                                                         notready_proxies.clone(),
+                                                         ready_proxies.clone(),
+                                                         inuse_proxies.clone(),
+                                                         &counters).await;
+
+                    /* The session may have expired while this request was
+                       waiting for it, do not leave its new proxy pinned */
+                    let current = sessions.lock().unwrap().get(&session_id).cloned();
+                    if !current.is_some_and(|s| Arc::ptr_eq(&s, &session)) {
+                        Self::end_session(&mut session_guard,
+                                          nr_of_proxies,
+                                          notready_proxies,
+                                          ready_proxies,
+                                          inuse_proxies);
+                    }
+                    response
+                }
+            },
+            /* Acknowledged with the id of the proxy the session was using */
+            ProxifyCommand::END_SESSION => {
+                Detail!("Processing command END_SESSION for session {}", session_id);
+                let session = sessions.lock().unwrap().remove(&session_id);
+                let proxy_id = match session {
+                    Some(session) => Self::end_session(&mut *session.lock().await,
+                                                       nr_of_proxies,
+                                                       notready_proxies,
+                                                       ready_proxies,
+                                                       inuse_proxies),
+                    None => None,
+                };
+                ProxifyResponse::new(session_id, ProxifyStatus::OK, proxy_id)
+            },
+            ProxifyCommand::STATS => {
+                Detail!("Processing command STATS");
+                let report = StatsReport::new(&counters,
+                                              nr_connections.load(Ordering::Relaxed),
+                                              &notready_proxies,
+                                              &ready_proxies,
+                                              &inuse_proxies,
+                                              &quarantined_proxies);
+                let mut response = ProxifyResponse::new(session_id, ProxifyStatus::OK, None);
+                response.add_tlv(ProxifyDataType::DATA, report.to_json().as_bytes());
+                response
+            },
+        };
+
+        response.request_id = request_id;
+        counters.record_request(parsed_data.command, response.status);
+        if let Err(e) = Self::send_response(&writer, &response).await {
+            Error!("Failed to send response to client: {}", e);
+        }
+        drop(done);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_accept(stream: TcpStream,
+                           busy: bool,
+                           exiting: Arc<AtomicBool>,
+                           nr_connections: Arc<AtomicUsize>,
+                           session_timeout: Duration,
+                           request_timeout: u16,
+                           drain_timeout: Duration,
+                           driver: Arc<TransferDriver>,
+                           strategy: &'static dyn SelectionStrategy,
+                           nr_of_proxies: usize,
+                           notready_proxies: ThreadSafeList,
+                           ready_proxies: ThreadSafeList,
+                           inuse_proxies: ThreadSafeList,
+                           quarantined_proxies: ThreadSafeList,
+                           counters: Arc<DaemonCounters>,
+                           ) {
         let mut authenticated = false;
-        let mut recv_data = [0 as u8; 1024];
+        let mut recv_data = [0_u8; 4096];
+        let mut frames = FrameReader::new();
+        let sessions: SessionMap = Arc::new(Mutex::new(HashMap::new()));
+        let mut workers: JoinSet<()> = JoinSet::new();
+        /* Signalled when the last command received for a session is done */
+        let mut session_tails: HashMap<u8, oneshot::Receiver<()>> = HashMap::new();
 
-        Detail!("Thread {} is running", nr_threads.lock().unwrap());
+        Detail!("Connection {} is running", nr_connections.load(Ordering::Relaxed));
 
-        while !exiting.load(Ordering::Relaxed) {
-            match stream.read(&mut recv_data) {
+        let peer_addr = stream.peer_addr();
+        /* The request tasks write their responses through the shared write
+           half, the lock keeps frames from interleaving */
+        let (mut reader, writer) = stream.into_split();
+        let writer: SharedWriter = Arc::new(tokio::sync::Mutex::new(writer));
+
+        'connection: while !exiting.load(Ordering::Relaxed) {
+            Self::expire_sessions(&sessions,
+                                  session_timeout,
+                                  nr_of_proxies,
+                                  &notready_proxies,
+                                  &ready_proxies,
+                                  &inuse_proxies);
+            while workers.try_join_next().is_some() {}
+
+            /* Wake up regularly to notice idle sessions and the daemon
+               exiting */
+            let read = match tokio::time::timeout(CONNECTION_POLL_INTERVAL, reader.read(&mut recv_data)).await {
+                Ok(r) => r,
+                /* A busy client that does not finish the handshake is not
+                   waited for */
+                Err(_) if busy => break,
+                Err(_) => continue,
+            };
+
+            match read {
                 Ok(size) if size > 0 => {
+                    frames.push(&recv_data[0..size]);
+
                     /* Check for the magic bytes */
                     if !authenticated {
-                        match Self::authenticate(&recv_data[0..MAGIC_BYTES.len()]) {
+                        let magic = match frames.take_bytes(MAGIC_BYTES.len()) {
+                            Some(m) => m,
+                            None => continue,
+                        };
+                        match Self::authenticate(&magic) {
                             Ok(_) => {
                                 authenticated = true;
                                 Inform!("Authentication successful");
                                 /* echo the data */
                                 Detail!("Sending magic data back");
-                                stream.write(&recv_data[0..MAGIC_BYTES.len()]).unwrap();
-                                continue;
+                                if let Err(e) = writer.lock().await.write_all(&magic).await {
+                                    Error!("Failed to send magic data back: {}", e);
+                                    break;
+                                }
+                                /* Tell an over-limit client right away
+                                   instead of waiting for its requests */
+                                if busy {
+                                    let response = ProxifyResponse::error(NO_SESSION,
+                                                                          ProxifyStatus::BUSY,
+                                                                          None,
+                                                                          "Too many connections");
+                                    let _ = Self::send_response(&writer, &response).await;
+                                    break;
+                                }
                             }
                             Err(errstr) => {
                                 Error!("Failed to authenticate: {}", errstr);
@@ -253,50 +936,207 @@ impl ProxifyDaemon {
                         }
                     }
 
-                    /* Expect a command from the client */
-                    Detail!("Received data from client");
-                    let parsed_data = match ProxifyData::unmarshal_bytes(&recv_data[0..size]) {
-                        Ok(d) => d,
-                        Err(e) => {
-                            Error!("Received invalid data from client: {}", e.to_string());
-                            break;
-                        },
-                    };
-
-                    let ready_proxies_clone = ready_proxies.clone();
-                    let inuse_proxies_clone = inuse_proxies.clone();
-
-                    match parsed_data.command {
-                        ProxifyCommand::REQUEST_GET => {
-                            Detail!("Processing command REQUEST_GET");
-                            let proxy = ProxifyDaemon::get_ready_proxy(
-                                ready_proxies_clone,
-                                inuse_proxies_clone
-                            );
-                            // TODO: Use proxy to send request!
-                        },
-                        ProxifyCommand::REQUEST_POST => {
-                            Detail!("Processing command REQUEST_POST");
-                        },
-                        ProxifyCommand::END_SESSION => {
+                    /* Hand every complete frame received so far to a task,
+                       the rest stays buffered until more data arrives */
+                    loop {
+                        let frame = match frames.next_frame() {
+                            Ok(Some(f)) => f,
+                            Ok(None) => break,
+                            Err(e) => {
+                                /* Tell the client why (e.g. an old protocol
+                                   version) before hanging up */
+                                Error!("Received an invalid frame from client: {}", e);
+                                let response = ProxifyResponse::error(0, ProxifyStatus::BAD_REQUEST, None, &e);
+                                let _ = Self::send_response(&writer, &response).await;
+                                break 'connection;
+                            },
+                        };
+
+                        /* Expect a command from the client */
+                        Detail!("Received data from client");
+                        let parsed_data = match ProxifyData::unmarshal_bytes(&frame) {
+                            Ok(d) => d,
+                            Err(e) => {
+                                Error!("Received invalid data from client: {}", e);
+                                let response = ProxifyResponse::error(0,
+                                                                      ProxifyStatus::BAD_REQUEST,
+                                                                      None,
+                                                                      &e.to_string());
+                                let _ = Self::send_response(&writer, &response).await;
+                                break 'connection;
+                            },
+                        };
+
+                        /* Without a session END_SESSION ends the whole
+                           connection */
+                        if parsed_data.command == ProxifyCommand::END_SESSION &&
+                           parsed_data.session == NO_SESSION {
                             Detail!("Processing command END_SESSION");
-                            break;
-                        },
-                    };
+                            break 'connection;
+                        }
+
+                        let (previous, done) = if parsed_data.session == NO_SESSION {
+                            (None, None)
+                        } else {
+                            let (done, tail) = oneshot::channel();
+                            (session_tails.insert(parsed_data.session, tail), Some(done))
+                        };
+                        workers.spawn(Self::handle_command(parsed_data,
+                                                           previous,
+                                                           done,
+                                                           writer.clone(),
+                                                           sessions.clone(),
+                                                           driver.clone(),
+                                                           request_timeout,
+                                                           strategy,
+                                                           nr_of_proxies,
+                                                           notready_proxies.clone(),
+                                                           ready_proxies.clone(),
+                                                           inuse_proxies.clone(),
+                                                           quarantined_proxies.clone(),
+                                                           nr_connections.clone(),
+                                                           counters.clone()));
+                    }
                 },
 
                 /* If we received 0 bytes, we're done */
                 Ok(_) => {
-                    Detail!("Gracefully closing the connection with {}", stream.peer_addr().unwrap());
+                    Detail!("Gracefully closing the connection with {:?}", peer_addr);
                     break;
                 },
 
-                Err(_) => {
-                    Error!("An error occurred, terminating connection with {}", stream.peer_addr().unwrap());
+                Err(e) => {
+                    Error!("An error occurred ({}), terminating connection with {:?}", e, peer_addr);
                     break;
                 }
             }
         }
-        *nr_threads.lock().unwrap() -= 1;
+
+        /* Let the requests in flight finish (and answer them if the client
+           is still there) before cleaning up their sessions. When shutting
+           down they only get drain_timeout and the client is told. */
+        if exiting.load(Ordering::Relaxed) {
+            if tokio::time::timeout(drain_timeout, Self::join_all(&mut workers)).await.is_err() {
+                Warn!("Abandoning {} request(s) of {:?}", workers.len(), peer_addr);
+                workers.shutdown().await;
+            }
+            let response = ProxifyResponse::error(NO_SESSION,
+                                                  ProxifyStatus::SHUTTING_DOWN,
+                                                  None,
+                                                  "The daemon is shutting down");
+            let _ = Self::send_response(&writer, &response).await;
+        } else {
+            Self::join_all(&mut workers).await;
+        }
+
+        /* Sessions do not outlive their connection */
+        let sessions: Vec<_> = sessions.lock().unwrap().drain().map(|(_, s)| s).collect();
+        for session in sessions {
+            Self::end_session(&mut *session.lock().await,
+                              nr_of_proxies,
+                              notready_proxies.clone(),
+                              ready_proxies.clone(),
+                              inuse_proxies.clone());
+        }
+        if !busy {
+            nr_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(base: u64, max: u64, max_failures: u32) -> BackoffPolicy {
+        BackoffPolicy {
+            base: Duration::from_secs(base),
+            max: Duration::from_secs(max),
+            max_failures,
+        }
+    }
+
+    fn list(ids: std::ops::Range<u16>) -> ThreadSafeList {
+        let proxies = ids.map(|id| {
+            let proxy = ProxyConn::new(id, ProxyConnProtocol::HTTP, String::from("127.0.0.1"), 1, None, None);
+            Arc::new(Mutex::new(proxy))
+        });
+        Arc::new(Mutex::new(proxies.collect()))
+    }
+
+    #[test]
+    fn released_proxies_keep_the_ready_pool_at_its_target() {
+        let nr_of_proxies = 2;
+        let notready = list(0..0);
+        let ready = list(0..2);
+        /* Taken by clients while the prepare tasks refilled ready */
+        let inuse = list(2..6);
+
+        let released: Vec<_> = inuse.lock().unwrap().iter().cloned().collect();
+        for proxy in released {
+            ProxifyDaemon::release_proxy(proxy, true, nr_of_proxies, notready.clone(), ready.clone(), inuse.clone());
+            assert_eq!(ready.lock().unwrap().len(), nr_of_proxies);
+        }
+        assert_eq!(notready.lock().unwrap().len(), 4);
+        assert!(inuse.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn released_proxy_fills_up_the_ready_pool() {
+        let notready = list(0..0);
+        let ready = list(0..1);
+        let inuse = list(1..3);
+
+        let released: Vec<_> = inuse.lock().unwrap().iter().cloned().collect();
+        for proxy in released {
+            ProxifyDaemon::release_proxy(proxy, true, 2, notready.clone(), ready.clone(), inuse.clone());
+        }
+        let ready_ids: Vec<u16> = ready.lock().unwrap().iter().map(|p| p.lock().unwrap().get_id()).collect();
+        assert_eq!(ready_ids, vec![0, 1]);
+        assert_eq!(notready.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_after_the_first_failure() {
+        let backoff = policy(1, 300, 10);
+        let delays: Vec<u64> = (1..=5).map(|n| backoff.delay(n).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16]);
+        /* No failures yet waits just as long as the first one */
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_is_capped_at_max() {
+        let backoff = policy(1, 300, 10);
+        assert_eq!(backoff.delay(9), Duration::from_secs(256));
+        assert_eq!(backoff.delay(10), Duration::from_secs(300));
+        assert_eq!(backoff.delay(20), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn backoff_survives_shift_overflow() {
+        let backoff = policy(5, 300, 0);
+        for n in [32, 33, 64, 1000, u32::MAX] {
+            assert_eq!(backoff.delay(n), Duration::from_secs(300), "{} failures", n);
+        }
+        /* The multiplication saturates too */
+        let backoff = BackoffPolicy { base: Duration::MAX, max: Duration::MAX, max_failures: 0 };
+        assert_eq!(backoff.delay(u32::MAX), Duration::MAX);
+    }
+
+    #[test]
+    fn quarantine_after_max_failures() {
+        let backoff = policy(1, 300, 3);
+        assert!(!backoff.should_quarantine(0));
+        assert!(!backoff.should_quarantine(2));
+        assert!(backoff.should_quarantine(3));
+        assert!(backoff.should_quarantine(4));
+    }
+
+    #[test]
+    fn zero_max_failures_never_quarantines() {
+        let backoff = policy(1, 300, 0);
+        assert!(!backoff.should_quarantine(0));
+        assert!(!backoff.should_quarantine(u32::MAX));
     }
 }
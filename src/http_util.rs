@@ -0,0 +1,157 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+use crate::common::VERBOSITY;
+use crate::common::verbose_print::VerbosityLevel;
+use crate::{Error, Warn};
+
+/* Just enough HTTP/1.1 for the admin and metrics endpoints: one request per
+   connection, answered and closed */
+
+pub struct HttpRequest {
+    pub method: String,
+    /* Without the query string */
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+/* How often the accept loop checks for the daemon exiting */
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/* Accept connections until "exiting" becomes true and hand each one to
+   handler in a task of its own, so a client that is slow to send its request
+   does not hold up the others. name is only used for logging. */
+pub async fn serve<F, Fut>(name: &'static str, listener: TcpListener, exiting: Arc<AtomicBool>, handler: F)
+    where F: Fn(TcpStream) -> Fut,
+          Fut: Future<Output = std::io::Result<()>> + Send + 'static {
+    let mut connections: JoinSet<()> = JoinSet::new();
+    while !exiting.load(Ordering::Relaxed) {
+        while connections.try_join_next().is_some() {}
+
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = tokio::time::sleep(POLL_INTERVAL) => continue,
+        };
+        match accepted {
+            Ok((stream, peer_addr)) => {
+                let connection = handler(stream);
+                connections.spawn(log_failure(name, peer_addr, connection));
+            },
+            Err(e) => Error!("Failed to accept {} connection: {}", name, e),
+        }
+    }
+    /* The requests still being read are bounded by READ_TIMEOUT */
+    while connections.join_next().await.is_some() {}
+}
+
+async fn log_failure(name: &'static str,
+                     peer_addr: SocketAddr,
+                     connection: impl Future<Output = std::io::Result<()>>) {
+    if let Err(e) = connection.await {
+        Warn!("The {} request from {} failed: {}", name, peer_addr, e);
+    }
+}
+
+/* Read the request line, the headers and a body of Content-Length bytes. A
+   request that can not be read is an HTTP status and a message to answer
+   with, the outer error is for the connection itself failing. */
+pub async fn read_request(stream: &mut TcpStream) -> std::io::Result<Result<HttpRequest, (u16, &'static str)>> {
+    match tokio::time::timeout(READ_TIMEOUT, read_request_inner(stream)).await {
+        Ok(r) => r,
+        Err(_) => Ok(Err((400, "Timed out reading the request"))),
+    }
+}
+
+async fn read_request_inner(stream: &mut TcpStream) -> std::io::Result<Result<HttpRequest, (u16, &'static str)>> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = [0_u8; 4096];
+
+    let head_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Ok(Err((413, "Request too large")));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(Err((400, "Incomplete request")));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(m), Some(t)) => (m.to_string(), t),
+        _ => return Ok(Err((400, "Invalid request line"))),
+    };
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut content_length = 0_usize;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = match value.trim().parse() {
+                    Ok(l) => l,
+                    Err(_) => return Ok(Err((400, "Invalid Content-Length"))),
+                };
+            }
+        }
+    }
+    if content_length > MAX_REQUEST_SIZE {
+        return Ok(Err((413, "Request too large")));
+    }
+
+    let mut body = buf.split_off(head_len);
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(Err((400, "Incomplete request body")));
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(content_length);
+
+    Ok(Ok(HttpRequest {
+        method,
+        path,
+        body,
+    }))
+}
+
+pub async fn write_response(stream: &mut TcpStream,
+                            code: u16,
+                            content_type: &str,
+                            body: &str) -> std::io::Result<()> {
+    let head = format!("HTTP/1.1 {} {}\r\n\
+                        Content-Type: {}\r\n\
+                        Content-Length: {}\r\n\
+                        Connection: close\r\n\r\n",
+                       code, reason(code), content_type, body.len());
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
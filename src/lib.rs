@@ -1,3 +1,7 @@
 pub mod proxy_conn;
+pub mod proxy_stats;
 pub mod proxify_data;
+pub mod proxify_frame;
+pub mod proxify_client;
+pub mod transfer_driver;
 pub mod common;
@@ -1,20 +1,24 @@
 use std::error::Error;
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use clap::{arg, command};
 use once_cell::sync::Lazy;
-use ctrlc;
 
-mod common;
+/* The shared modules live in the library crate, import them at the root so
+   the daemon modules can keep referring to them through crate:: */
+use proxify::{common, proxy_conn, proxify_data, proxify_frame, transfer_driver};
+use proxify::{Error, Warn, Inform, Detail, Spam};
 use crate::common::VERBOSITY;
 use crate::common::verbose_print::VerbosityLevel;
 mod daemon;
 use daemon::ProxifyDaemon;
 mod proxify_config;
+mod selection_strategy;
+mod stats_report;
+mod http_util;
+mod admin_api;
+mod metrics;
 use proxify_config::ProxifyConfig;
-mod proxy_conn;
-mod proxify_data;
 
 static EXITING: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
 
@@ -25,10 +29,8 @@ fn main() -> Result<(), Box<dyn Error>> {
     ]).get_matches();
 
     let dbg_lvl = match cmd_args.get_one::<String>("debug") {
-        Some(v) => match String::from(v).trim().parse::<u32>() {
-            Ok(n) => n,
-            Err(_e) => 0, // Move this error checking to the command!().
-        },
+        // Move this error checking to the command!().
+        Some(v) => String::from(v).trim().parse::<u32>().unwrap_or_default(),
         None => 0,
     };
 
@@ -61,15 +63,15 @@ fn main() -> Result<(), Box<dyn Error>> {
     };
 
     {
-        /* Handle CTRL-C sigterm */
+        /* Handle CTRL-C sigterm. The daemon notices the flag on its own and
+           shuts down gracefully, a second CTRL-C does not wait for that. */
         let exiting_clone = EXITING.clone();
-        let listener_addr = listener.get_bind_addr().clone();
-        let listener_port = listener.get_bind_port();
         ctrlc::set_handler(move || {
+            if exiting_clone.swap(true, Ordering::SeqCst) {
+                Inform!("Caught sigterm again, exiting now");
+                std::process::exit(1);
+            }
             Inform!("Caught sigterm, exiting...");
-            exiting_clone.store(true, Ordering::SeqCst);
-            /* We connect to the listening server to wake it up and see that we are exiting */
-            let _ = TcpStream::connect((listener_addr.as_str(), listener_port));
         })
         .expect("Error setting Ctrl+C handler");
     }
@@ -0,0 +1,209 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::common::VERBOSITY;
+use crate::common::verbose_print::VerbosityLevel;
+use crate::{Inform, Spam};
+use crate::daemon::ThreadSafeList;
+use crate::http_util::{self, read_request, write_response};
+use crate::stats_report::DaemonCounters;
+
+/* Upper bounds of the histogram buckets, in seconds */
+const BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/* Observations counted into fixed buckets, as Prometheus wants them */
+#[derive(Default)]
+pub struct Histogram {
+    /* Per bucket, not cumulative, the last one is +Inf */
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: f64) {
+        let bucket = BUCKETS.iter()
+            .position(|b| value <= *b)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /* Append the _bucket, _sum and _count samples, labels being the ones
+       shared by all of them, e.g. proxy="1" */
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0_u64;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let le = match BUCKETS.get(i) {
+                Some(b) => b.to_string(),
+                None => String::from("+Inf"),
+            };
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, cumulative);
+    }
+}
+
+/* Serves GET /metrics in the Prometheus text format */
+pub struct MetricsServer {
+    notready_proxies: ThreadSafeList,
+    ready_proxies: ThreadSafeList,
+    inuse_proxies: ThreadSafeList,
+    quarantined_proxies: ThreadSafeList,
+    nr_connections: Arc<AtomicUsize>,
+    counters: Arc<DaemonCounters>,
+}
+
+impl MetricsServer {
+    pub fn new(notready_proxies: ThreadSafeList,
+               ready_proxies: ThreadSafeList,
+               inuse_proxies: ThreadSafeList,
+               quarantined_proxies: ThreadSafeList,
+               nr_connections: Arc<AtomicUsize>,
+               counters: Arc<DaemonCounters>) -> Self {
+        MetricsServer {
+            notready_proxies,
+            ready_proxies,
+            inuse_proxies,
+            quarantined_proxies,
+            nr_connections,
+            counters,
+        }
+    }
+
+    /* Run as a task until "exiting" becomes true */
+    pub async fn serve(self, listener: TcpListener, exiting: Arc<AtomicBool>) {
+        Inform!("Metrics listening on {:?}", listener.local_addr());
+        let metrics = Arc::new(self);
+        http_util::serve("metrics", listener, exiting, move |stream| {
+            let metrics = metrics.clone();
+            async move { metrics.handle_connection(stream).await }
+        }).await;
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        match read_request(&mut stream).await? {
+            Ok(request) if request.method == "GET" && request.path == "/metrics" => {
+                Spam!("Serving metrics");
+                write_response(&mut stream, 200, "text/plain; version=0.0.4", &self.render()).await
+            },
+            Ok(_) => write_response(&mut stream, 404, "text/plain", "Only GET /metrics is served\n").await,
+            Err((code, msg)) => write_response(&mut stream, code, "text/plain", msg).await,
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP proxify_proxies Proxies by pool.");
+        let _ = writeln!(out, "# TYPE proxify_proxies gauge");
+        for (state, list) in [("not_ready", &self.notready_proxies),
+                              ("ready", &self.ready_proxies),
+                              ("in_use", &self.inuse_proxies),
+                              ("quarantined", &self.quarantined_proxies)] {
+            let _ = writeln!(out, "proxify_proxies{{state=\"{}\"}} {}", state, list.lock().unwrap().len());
+        }
+
+        let _ = writeln!(out, "# HELP proxify_client_connections Connected clients, not counting those answered with BUSY.");
+        let _ = writeln!(out, "# TYPE proxify_client_connections gauge");
+        let _ = writeln!(out, "proxify_client_connections {}", self.nr_connections.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP proxify_requests_total Requests answered, by command and status.");
+        let _ = writeln!(out, "# TYPE proxify_requests_total counter");
+        let mut requests = self.counters.get_request_counts();
+        requests.sort_by_key(|((command, status), _)| (*command as u8, *status as u8));
+        for ((command, status), count) in requests {
+            let _ = writeln!(out, "proxify_requests_total{{command=\"{:?}\",status=\"{:?}\"}} {}", command, status, count);
+        }
+
+        let (attempts, failures) = self.counters.get_prepare_counts();
+        let _ = writeln!(out, "# HELP proxify_prepare_attempts_total Health checks run.");
+        let _ = writeln!(out, "# TYPE proxify_prepare_attempts_total counter");
+        let _ = writeln!(out, "proxify_prepare_attempts_total {}", attempts);
+        let _ = writeln!(out, "# HELP proxify_prepare_failures_total Health checks failed.");
+        let _ = writeln!(out, "# TYPE proxify_prepare_failures_total counter");
+        let _ = writeln!(out, "proxify_prepare_failures_total {}", failures);
+
+        let name = "proxify_upstream_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Duration of client requests through a proxy, by proxy and protocol.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        self.counters.with_upstream_latency(|latencies| {
+            let mut keys: Vec<_> = latencies.keys().collect();
+            keys.sort();
+            for key in keys {
+                let (proxy_id, protocol) = key;
+                let labels = format!("proxy=\"{}\",protocol=\"{}\"", proxy_id, protocol);
+                latencies[key].render(&mut out, name, &labels);
+            }
+        });
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        /* On a bucket bound, between bounds and past the last one */
+        for value in [0.25, 0.5, 4.0, 100.0] {
+            histogram.observe(value);
+        }
+        let mut out = String::new();
+        histogram.render(&mut out, "d", "proxy=\"1\"");
+        assert_eq!(out, "\
+d_bucket{proxy=\"1\",le=\"0.05\"} 0
+d_bucket{proxy=\"1\",le=\"0.1\"} 0
+d_bucket{proxy=\"1\",le=\"0.25\"} 1
+d_bucket{proxy=\"1\",le=\"0.5\"} 2
+d_bucket{proxy=\"1\",le=\"1\"} 2
+d_bucket{proxy=\"1\",le=\"2.5\"} 2
+d_bucket{proxy=\"1\",le=\"5\"} 3
+d_bucket{proxy=\"1\",le=\"10\"} 3
+d_bucket{proxy=\"1\",le=\"30\"} 3
+d_bucket{proxy=\"1\",le=\"60\"} 3
+d_bucket{proxy=\"1\",le=\"+Inf\"} 4
+d_sum{proxy=\"1\"} 104.75
+d_count{proxy=\"1\"} 4
+");
+    }
+
+    #[test]
+    fn upstream_durations_are_labelled_by_proxy_and_protocol() {
+        let list = || Arc::new(Mutex::new(VecDeque::new()));
+        let counters = Arc::new(DaemonCounters::new());
+        counters.record_upstream_latency(2, String::from("socks5"), Duration::from_millis(250));
+        counters.record_upstream_latency(1, String::from("http"), Duration::from_millis(500));
+        counters.record_upstream_latency(1, String::from("http"), Duration::from_secs(120));
+        let metrics = MetricsServer::new(list(), list(), list(), list(), Arc::new(AtomicUsize::new(0)), counters);
+
+        let out = metrics.render();
+        let lines: Vec<&str> = out.lines()
+            .filter(|l| l.starts_with("proxify_upstream_duration_seconds"))
+            .filter(|l| l.contains("le=\"0.5\"") || l.contains("+Inf") || !l.contains("_bucket"))
+            .collect();
+        assert_eq!(lines, vec![
+            "proxify_upstream_duration_seconds_bucket{proxy=\"1\",protocol=\"http\",le=\"0.5\"} 1",
+            "proxify_upstream_duration_seconds_bucket{proxy=\"1\",protocol=\"http\",le=\"+Inf\"} 2",
+            "proxify_upstream_duration_seconds_sum{proxy=\"1\",protocol=\"http\"} 120.5",
+            "proxify_upstream_duration_seconds_count{proxy=\"1\",protocol=\"http\"} 2",
+            "proxify_upstream_duration_seconds_bucket{proxy=\"2\",protocol=\"socks5\",le=\"0.5\"} 1",
+            "proxify_upstream_duration_seconds_bucket{proxy=\"2\",protocol=\"socks5\",le=\"+Inf\"} 1",
+            "proxify_upstream_duration_seconds_sum{proxy=\"2\",protocol=\"socks5\"} 0.25",
+            "proxify_upstream_duration_seconds_count{proxy=\"2\",protocol=\"socks5\"} 1",
+        ]);
+        assert!(out.contains("# TYPE proxify_upstream_duration_seconds histogram\n"));
+        assert!(out.contains("proxify_proxies{state=\"ready\"} 0\n"));
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::common::VERBOSITY;
+use crate::common::verbose_print::VerbosityLevel;
+use crate::{Detail, Spam};
+use crate::proxify_data::{ProxifyCommand, ProxifyData, ProxifyDataError, ProxifyResponse, ProxifyStatus};
+use crate::proxify_frame::{MAGIC_BYTES, read_frame, write_frame};
+
+#[derive(Debug)]
+pub enum ProxifyClientError {
+    Io(io::Error),
+    /* The daemon did not echo the magic bytes */
+    Handshake,
+    /* The daemon sent something that is not a valid response */
+    Decode(ProxifyDataError),
+    /* The request could not be built, e.g. a missing URL */
+    InvalidRequest(String),
+    /* receive() was called without any request in flight */
+    NothingInFlight,
+    /* The daemon is shutting down, requests still in flight are lost */
+    ShuttingDown,
+    /* The daemon has too many clients and hung up, try again later */
+    Busy,
+}
+
+impl fmt::Display for ProxifyClientError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxifyClientError::Io(e) => write!(fmt, "I/O error: {}", e),
+            ProxifyClientError::Handshake => fmt.write_str("Daemon did not accept the magic bytes"),
+            ProxifyClientError::Decode(e) => write!(fmt, "Invalid response: {}", e),
+            ProxifyClientError::InvalidRequest(e) => write!(fmt, "Invalid request: {}", e),
+            ProxifyClientError::NothingInFlight => fmt.write_str("No requests waiting for a response"),
+            ProxifyClientError::ShuttingDown => fmt.write_str("The daemon is shutting down"),
+            ProxifyClientError::Busy => fmt.write_str("The daemon has too many clients"),
+        }
+    }
+}
+
+impl std::error::Error for ProxifyClientError {}
+
+impl From<io::Error> for ProxifyClientError {
+    fn from(e: io::Error) -> Self {
+        ProxifyClientError::Io(e)
+    }
+}
+
+/* A client for the proxify daemon. It connects lazily and performs the
+   magic bytes handshake. send() (and get(), post() etc.) send one request
+   and wait for its reply, submit() and receive() pipeline many requests over
+   the one connection, matching replies to requests by request id.
+
+   If the connection turns out to be broken while sending a request, and no
+   other requests are waiting for replies, the client reconnects and sends it
+   again (up to max_reconnects times). If it breaks (or times out) while
+   waiting for a reply the error is returned, since the requests may already
+   have been performed, and the next call reconnects. */
+pub struct ProxifyClient {
+    addr: String,
+    timeout: Duration,
+    max_reconnects: u8,
+    session: u8,
+    stream: Option<TcpStream>,
+    next_request_id: u32,
+    /* Sent but not answered yet */
+    in_flight: HashSet<u32>,
+    /* Answered while waiting for another request */
+    pending: HashMap<u32, ProxifyResponse>,
+}
+
+impl ProxifyClient {
+    /* Twice the daemon's default request_timeout, so a request that runs
+       into it is answered with UPSTREAM_TIMEOUT before the socket gives up */
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+    const DEFAULT_MAX_RECONNECTS: u8 = 3;
+
+    /* addr is anything resolvable, e.g. "127.0.0.1:65432" */
+    pub fn new(addr: &str) -> Self {
+        ProxifyClient {
+            addr: addr.to_string(),
+            timeout: Self::DEFAULT_TIMEOUT,
+            max_reconnects: Self::DEFAULT_MAX_RECONNECTS,
+            session: 0,
+            stream: None,
+            next_request_id: 0,
+            in_flight: HashSet::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /* Used for connecting and for every read and write on the socket. Keep
+       it above the daemon's upstream timeouts. */
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        if let Some(stream) = &self.stream {
+            let _ = stream.set_read_timeout(Some(timeout));
+            let _ = stream.set_write_timeout(Some(timeout));
+        }
+    }
+
+    pub fn set_max_reconnects(&mut self, max_reconnects: u8) {
+        self.max_reconnects = max_reconnects;
+    }
+
+    /* Requests sent by get(), post() and request() belong to this session.
+       A non-zero session keeps the same proxy and cookies until
+       end_session() (or the connection is lost), 0 means no session. */
+    pub fn set_session(&mut self, session: u8) {
+        self.session = session;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /* Connect and authenticate, replacing any existing connection */
+    pub fn connect(&mut self) -> Result<(), ProxifyClientError> {
+        self.disconnect();
+
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound,
+                                          format!("No address found for '{}'", self.addr));
+        for addr in self.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    stream.set_nodelay(true)?;
+                    return self.handshake(stream);
+                },
+                Err(e) => last_err = e,
+            }
+        }
+        Err(ProxifyClientError::Io(last_err))
+    }
+
+    fn handshake(&mut self, mut stream: TcpStream) -> Result<(), ProxifyClientError> {
+        stream.write_all(&MAGIC_BYTES)?;
+        let mut reply = [0_u8; MAGIC_BYTES.len()];
+        stream.read_exact(&mut reply)?;
+        if reply != MAGIC_BYTES {
+            return Err(ProxifyClientError::Handshake);
+        }
+        Detail!("Connected to proxify daemon at {}", self.addr);
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    /* Forget the connection along with the requests still waiting on it */
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.in_flight.clear();
+    }
+
+    /* Send a request without waiting for its response. The request id is
+       assigned here and returned, receive() hands out the response later. */
+    pub fn submit(&mut self, mut request: ProxifyData) -> Result<u32, ProxifyClientError> {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        request.request_id = request_id;
+        let payload = request.marshal_bytes();
+
+        let mut attempt = 0;
+        loop {
+            if self.stream.is_none() {
+                self.connect()?;
+            }
+            let stream = self.stream.as_mut().unwrap();
+            match write_frame(stream, &payload) {
+                Ok(_) => break,
+                /* Reconnecting would lose the replies of the other requests */
+                Err(e) if attempt < self.max_reconnects && self.in_flight.is_empty() => {
+                    Spam!("Sending to {} failed ({}), reconnecting", self.addr, e);
+                    self.disconnect();
+                    attempt += 1;
+                },
+                Err(e) => {
+                    self.disconnect();
+                    return Err(e.into());
+                },
+            }
+        }
+        self.in_flight.insert(request_id);
+        Ok(request_id)
+    }
+
+    /* Wait for the next response to any submitted request */
+    pub fn receive(&mut self) -> Result<ProxifyResponse, ProxifyClientError> {
+        if let Some(request_id) = self.pending.keys().next().copied() {
+            return Ok(self.pending.remove(&request_id).unwrap());
+        }
+        if self.in_flight.is_empty() {
+            return Err(ProxifyClientError::NothingInFlight);
+        }
+        self.read_response()
+    }
+
+    /* Wait for the response to one submitted request, keeping the responses
+       to other requests that arrive in the meantime for receive() */
+    pub fn receive_for(&mut self, request_id: u32) -> Result<ProxifyResponse, ProxifyClientError> {
+        loop {
+            if let Some(response) = self.pending.remove(&request_id) {
+                return Ok(response);
+            }
+            if !self.in_flight.contains(&request_id) {
+                return Err(ProxifyClientError::NothingInFlight);
+            }
+            let response = self.read_response()?;
+            if response.request_id == request_id {
+                return Ok(response);
+            }
+            self.pending.insert(response.request_id, response);
+        }
+    }
+
+    fn read_response(&mut self) -> Result<ProxifyResponse, ProxifyClientError> {
+        let stream = match self.stream.as_mut() {
+            Some(s) => s,
+            None => return Err(ProxifyClientError::NothingInFlight),
+        };
+        let reply = match read_frame(stream) {
+            Ok(r) => r,
+            Err(e) => {
+                self.disconnect();
+                return Err(e.into());
+            },
+        };
+        let response = ProxifyResponse::unmarshal_bytes(&reply).map_err(ProxifyClientError::Decode)?;
+        /* Both are sent unprompted (request_id 0) right before the daemon
+           hangs up */
+        if response.status == ProxifyStatus::SHUTTING_DOWN {
+            self.disconnect();
+            return Err(ProxifyClientError::ShuttingDown);
+        }
+        if response.status == ProxifyStatus::BUSY {
+            self.disconnect();
+            return Err(ProxifyClientError::Busy);
+        }
+        self.in_flight.remove(&response.request_id);
+        Ok(response)
+    }
+
+    /* Send a request and wait for its response */
+    pub fn send(&mut self, request: ProxifyData) -> Result<ProxifyResponse, ProxifyClientError> {
+        let request_id = self.submit(request)?;
+        self.receive_for(request_id)
+    }
+
+    pub fn get(&mut self, url: &str, headers: &[&str]) -> Result<ProxifyResponse, ProxifyClientError> {
+        let mut builder = ProxifyData::builder(ProxifyCommand::REQUEST_GET)
+            .session(self.session)
+            .url(url);
+        for h in headers {
+            builder = builder.header(h);
+        }
+        let request = builder.build().map_err(ProxifyClientError::InvalidRequest)?;
+        self.send(request)
+    }
+
+    pub fn post(&mut self,
+                url: &str,
+                headers: &[&str],
+                body: &[u8]) -> Result<ProxifyResponse, ProxifyClientError> {
+        let mut builder = ProxifyData::builder(ProxifyCommand::REQUEST_POST)
+            .session(self.session)
+            .url(url);
+        for h in headers {
+            builder = builder.header(h);
+        }
+        let request = builder.body(body).build().map_err(ProxifyClientError::InvalidRequest)?;
+        self.send(request)
+    }
+
+    /* A request with any HTTP method, e.g. "HEAD" or "PUT" */
+    pub fn request(&mut self,
+                   method: &str,
+                   url: &str,
+                   headers: &[&str],
+                   body: &[u8]) -> Result<ProxifyResponse, ProxifyClientError> {
+        let mut builder = ProxifyData::builder(ProxifyCommand::REQUEST)
+            .session(self.session)
+            .url(url)
+            .method(method);
+        for h in headers {
+            builder = builder.header(h);
+        }
+        let request = builder.body(body).build().map_err(ProxifyClientError::InvalidRequest)?;
+        self.send(request)
+    }
+
+    /* End the current session, releasing its proxy in the daemon */
+    pub fn end_session(&mut self) -> Result<ProxifyResponse, ProxifyClientError> {
+        if self.session == 0 {
+            return Err(ProxifyClientError::InvalidRequest(String::from("No session set")));
+        }
+        let request = ProxifyData::new(self.session, ProxifyCommand::END_SESSION);
+        self.send(request)
+    }
+
+    /* Ask the daemon for its state, the body of the response is JSON */
+    pub fn stats(&mut self) -> Result<ProxifyResponse, ProxifyClientError> {
+        let request = ProxifyData::new(0, ProxifyCommand::STATS);
+        self.send(request)
+    }
+
+    /* Tell the daemon we are done and close the connection */
+    pub fn close(&mut self) -> Result<(), ProxifyClientError> {
+        self.in_flight.clear();
+        if let Some(mut stream) = self.stream.take() {
+            let request = ProxifyData::new(0, ProxifyCommand::END_SESSION);
+            write_frame(&mut stream, &request.marshal_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ProxifyClient {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
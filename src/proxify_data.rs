@@ -1,16 +1,23 @@
 use std::string::String;
 use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::fmt;
 
 // TODO: remove logging when all works
 use crate::common::VERBOSITY;
 use crate::common::verbose_print::VerbosityLevel;
-use crate::{Error, Warn, Inform, Detail, Spam};
+use crate::Spam;
 
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ProxifyCommand {
     REQUEST_GET = 1,
     REQUEST_POST = 2,
     END_SESSION = 3,
+    /* A request with the HTTP method given in a METHOD TLV */
+    REQUEST = 4,
+    /* Asks for the state of the daemon, answered with JSON in a DATA TLV */
+    STATS = 5,
 }
 
 impl TryFrom<u8> for ProxifyCommand {
@@ -21,16 +28,27 @@ impl TryFrom<u8> for ProxifyCommand {
             x if x == ProxifyCommand::REQUEST_GET as u8 => Ok(ProxifyCommand::REQUEST_GET),
             x if x == ProxifyCommand::REQUEST_POST as u8 => Ok(ProxifyCommand::REQUEST_POST),
             x if x == ProxifyCommand::END_SESSION as u8 => Ok(ProxifyCommand::END_SESSION),
+            x if x == ProxifyCommand::REQUEST as u8 => Ok(ProxifyCommand::REQUEST),
+            x if x == ProxifyCommand::STATS as u8 => Ok(ProxifyCommand::STATS),
             _ => Err(String::from("Invalid ProxifyCommand")),
         }
     }
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ProxifyDataType {
     URL = 1,
     HEADER = 2,
     DATA = 3,
+    /* HTTP status code of the upstream response, 2 bytes big-endian */
+    STATUS = 4,
+    /* Human readable error message, sent instead of STATUS on failure */
+    ERROR = 5,
+    /* HTTP method name (e.g. "PUT") for the REQUEST command */
+    METHOD = 6,
+    /* Name of the proxy selection strategy to use for this request */
+    STRATEGY = 7,
 }
 
 /* Read more on the code below:
@@ -44,74 +62,424 @@ impl TryFrom<u8> for ProxifyDataType {
             x if x == ProxifyDataType::URL as u8 => Ok(ProxifyDataType::URL),
             x if x == ProxifyDataType::HEADER as u8 => Ok(ProxifyDataType::HEADER),
             x if x == ProxifyDataType::DATA as u8 => Ok(ProxifyDataType::DATA),
+            x if x == ProxifyDataType::STATUS as u8 => Ok(ProxifyDataType::STATUS),
+            x if x == ProxifyDataType::ERROR as u8 => Ok(ProxifyDataType::ERROR),
+            x if x == ProxifyDataType::METHOD as u8 => Ok(ProxifyDataType::METHOD),
+            x if x == ProxifyDataType::STRATEGY as u8 => Ok(ProxifyDataType::STRATEGY),
             _ => Err(String::from("Invalid ProxifyDataType")),
         }
     }
 }
 
+/* Outcome of a client command, sent back in every ProxifyResponse */
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxifyStatus {
+    OK = 0,
+    NO_PROXY_READY = 1,
+    UPSTREAM_TIMEOUT = 2,
+    UPSTREAM_ERROR = 3,
+    BAD_REQUEST = 4,
+    /* The daemon is at its connection limit, try again later */
+    BUSY = 5,
+    /* Sent unasked (request id 0) right before the daemon hangs up because
+       it is shutting down */
+    SHUTTING_DOWN = 6,
+}
+
+impl TryFrom<u8> for ProxifyStatus {
+    type Error = String;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            x if x == ProxifyStatus::OK as u8 => Ok(ProxifyStatus::OK),
+            x if x == ProxifyStatus::NO_PROXY_READY as u8 => Ok(ProxifyStatus::NO_PROXY_READY),
+            x if x == ProxifyStatus::UPSTREAM_TIMEOUT as u8 => Ok(ProxifyStatus::UPSTREAM_TIMEOUT),
+            x if x == ProxifyStatus::UPSTREAM_ERROR as u8 => Ok(ProxifyStatus::UPSTREAM_ERROR),
+            x if x == ProxifyStatus::BAD_REQUEST as u8 => Ok(ProxifyStatus::BAD_REQUEST),
+            x if x == ProxifyStatus::BUSY as u8 => Ok(ProxifyStatus::BUSY),
+            x if x == ProxifyStatus::SHUTTING_DOWN as u8 => Ok(ProxifyStatus::SHUTTING_DOWN),
+            _ => Err(String::from("Invalid ProxifyStatus")),
+        }
+    }
+}
+
+/* Everything that can go wrong when decoding bytes from the wire */
+#[derive(Debug, PartialEq)]
+pub enum ProxifyDataError {
+    /* Fewer bytes than the fixed fields before the TLVs */
+    TruncatedHeader,
+    UnknownCommand(u8),
+    UnknownTlvType(u8),
+    /* A TLV announces more value bytes than are left */
+    LengthOverflow { needed: usize, available: usize },
+    /* Bytes left over that are too few to form a TLV header */
+    TrailingGarbage(usize),
+    UnknownStatus(u8),
+}
+
+impl fmt::Display for ProxifyDataError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxifyDataError::TruncatedHeader =>
+                write!(fmt, "Data too short to hold the fixed header fields"),
+            ProxifyDataError::UnknownCommand(c) =>
+                write!(fmt, "Invalid ProxifyCommand {}", c),
+            ProxifyDataError::UnknownTlvType(t) =>
+                write!(fmt, "Invalid ProxifyDataType {}", t),
+            ProxifyDataError::LengthOverflow { needed, available } =>
+                write!(fmt, "Invalid TLV found, not enough data (need {}, found {})", needed, available),
+            ProxifyDataError::TrailingGarbage(n) =>
+                write!(fmt, "{} trailing byte(s) after the last TLV", n),
+            ProxifyDataError::UnknownStatus(s) =>
+                write!(fmt, "Invalid ProxifyStatus {}", s),
+        }
+    }
+}
+
+impl std::error::Error for ProxifyDataError {}
+
+#[derive(Debug, PartialEq)]
 pub struct ProxifyData {
     pub session: u8,
     pub command: ProxifyCommand,
-    pub data: Vec<(ProxifyDataType, u8, Vec<u8>)>,
+    /* Chosen by the client and echoed in the response, so several requests
+       can be in flight on one connection */
+    pub request_id: u32,
+    pub data: Vec<(ProxifyDataType, u32, Vec<u8>)>,
 }
 
 impl ProxifyData {
-    pub fn unmarshal_bytes(data: &[u8]) -> Result<Self, String> {
+    /* The maximum value length a single TLV can carry */
+    pub const MAX_TLV_LENGTH: usize = u32::MAX as usize;
+
+    pub fn new(session: u8, command: ProxifyCommand) -> Self {
+        ProxifyData {
+            session,
+            command,
+            request_id: 0,
+            data: Vec::new(),
+        }
+    }
+
+    /* Start building a command, see ProxifyDataBuilder */
+    pub fn builder(command: ProxifyCommand) -> ProxifyDataBuilder {
+        ProxifyDataBuilder {
+            data: ProxifyData::new(0, command),
+        }
+    }
+
+    /* Add a value as one or more TLVs of the given type. Values longer than
+       a single TLV can carry are split into consecutive TLVs, the receiver
+       is expected to concatenate them (only meaningful for DATA). Empty
+       values are not added at all. */
+    pub fn add_tlv(&mut self, tlv_type: ProxifyDataType, value: &[u8]) {
+        push_tlv(&mut self.data, tlv_type, value);
+    }
+
+    /* Returns the first URL TLV, if any */
+    pub fn get_url(&self) -> Option<String> {
+        self.data.iter()
+            .find(|(t, _, _)| *t == ProxifyDataType::URL)
+            .map(|(_, _, v)| String::from_utf8_lossy(v).to_string())
+    }
+
+    /* Returns the first METHOD TLV, if any */
+    pub fn get_method(&self) -> Option<String> {
+        self.data.iter()
+            .find(|(t, _, _)| *t == ProxifyDataType::METHOD)
+            .map(|(_, _, v)| String::from_utf8_lossy(v).to_string())
+    }
+
+    /* Returns the first STRATEGY TLV, if any */
+    pub fn get_strategy(&self) -> Option<String> {
+        self.data.iter()
+            .find(|(t, _, _)| *t == ProxifyDataType::STRATEGY)
+            .map(|(_, _, v)| String::from_utf8_lossy(v).to_string())
+    }
+
+    /* Returns all HEADER TLVs, one header line each */
+    pub fn get_headers(&self) -> Vec<String> {
+        self.data.iter()
+            .filter(|(t, _, _)| *t == ProxifyDataType::HEADER)
+            .map(|(_, _, v)| String::from_utf8_lossy(v).to_string())
+            .collect()
+    }
+
+    /* Returns the concatenation of all DATA TLVs */
+    pub fn get_body(&self) -> Vec<u8> {
+        self.data.iter()
+            .filter(|(t, _, _)| *t == ProxifyDataType::DATA)
+            .flat_map(|(_, _, v)| v.iter().copied())
+            .collect()
+    }
+
+    pub fn marshal_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec!(self.session, self.command as u8);
+        bytes.extend_from_slice(&self.request_id.to_be_bytes());
+        marshal_tlvs(&self.data, &mut bytes);
+        bytes
+    }
+
+    /* Decode a ProxifyData from its wire format. Any byte sequence is
+       handled without panicking, invalid input results in an error. */
+    pub fn unmarshal_bytes(data: &[u8]) -> Result<Self, ProxifyDataError> {
+        if data.len() < 6 {
+            return Err(ProxifyDataError::TruncatedHeader);
+        }
         let session = data[0];
         let command: ProxifyCommand = match data[1].try_into() {
             Ok(enum_val) => enum_val,
-            Err(e) => return Err(String::from("Invalid ProxifyCommand")),
-        };
-        let parsed_data = match ProxifyData::parse_tlvs(&data[2..]) {
-            Ok(d) => d,
-            Err(e) => return Err(e),
+            Err(_) => return Err(ProxifyDataError::UnknownCommand(data[1])),
         };
+        let request_id = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let parsed_data = parse_tlvs(&data[6..])?;
 
         Ok(ProxifyData {
-            session: session,
-            command: command,
+            session,
+            command,
+            request_id,
             data: parsed_data,
         })
     }
+}
 
-    fn parse_tlvs(data: &[u8]) -> Result<Vec<(ProxifyDataType, u8, Vec<u8>)>, String> {
-        let mut tlvs: Vec<(ProxifyDataType, u8, Vec<u8>)> = Vec::new();
-        let mut begin = 0;
-        let end = data.len();
+/* Builds a ProxifyData for clients, making sure the TLVs the daemon needs
+   for the command are present:
 
-        loop {
-            Spam!("*** loop, begin at {}, end at {}", begin, end);
-            if begin + 3 > end { break; }
+   let request = ProxifyData::builder(ProxifyCommand::REQUEST)
+       .session(1)
+       .url("http://example.com")
+       .method("PUT")
+       .header("Content-Type: application/json")
+       .body(b"{}")
+       .build()?;
+*/
+pub struct ProxifyDataBuilder {
+    data: ProxifyData,
+}
 
-            Spam!("*** loop, try_into {}", data[begin]);
-            let tlv_type: ProxifyDataType = match data[begin].try_into() {
-                Ok(enum_val) => enum_val,
-                Err(_) => return Err(String::from("Invalid u8 for ProxyDataType")),
-            };
+impl ProxifyDataBuilder {
+    pub fn session(mut self, session: u8) -> Self {
+        self.data.session = session;
+        self
+    }
 
-            let tlv_length: u8 = data[begin + 1];
-            Spam!("*** loop, tlv_length {}", tlv_length);
+    pub fn request_id(mut self, request_id: u32) -> Self {
+        self.data.request_id = request_id;
+        self
+    }
 
-            if begin + 2 + (tlv_length as usize) > end {
-                return Err(format!("Invalid TLV found, not enough data (need {}, found {})",
-                                   tlv_length,
-                                   end - begin + 3));
-            }
+    pub fn url(mut self, url: &str) -> Self {
+        self.data.add_tlv(ProxifyDataType::URL, url.as_bytes());
+        self
+    }
 
-            let mut tlv_value: Vec<u8> = Vec::new();
+    pub fn method(mut self, method: &str) -> Self {
+        self.data.add_tlv(ProxifyDataType::METHOD, method.as_bytes());
+        self
+    }
 
-            let slice_begin = begin + 2;
-            let slice_end = begin + 2 + tlv_length as usize;
-            tlv_value.extend_from_slice(&data[slice_begin..slice_end]);
-            tlvs.push((tlv_type, tlv_length, tlv_value));
+    /* How the daemon picks the proxy, e.g. "lowest_latency" */
+    pub fn strategy(mut self, strategy: &str) -> Self {
+        self.data.add_tlv(ProxifyDataType::STRATEGY, strategy.as_bytes());
+        self
+    }
 
-            // For every loop we move one TLV forward (T, L and D[size])
-            begin += 2 + (tlv_length as usize);
-            if begin >= end {
-                break;
-            }
+    /* A single header line, e.g. "Accept: text/html" */
+    pub fn header(mut self, header: &str) -> Self {
+        self.data.add_tlv(ProxifyDataType::HEADER, header.as_bytes());
+        self
+    }
+
+    /* Can be called several times, the parts are concatenated */
+    pub fn body(mut self, body: &[u8]) -> Self {
+        self.data.add_tlv(ProxifyDataType::DATA, body);
+        self
+    }
+
+    pub fn build(self) -> Result<ProxifyData, String> {
+        let has = |tlv_type: ProxifyDataType| self.data.data.iter().any(|(t, _, _)| *t == tlv_type);
+        match self.data.command {
+            ProxifyCommand::REQUEST_GET | ProxifyCommand::REQUEST_POST if !has(ProxifyDataType::URL) =>
+                Err(String::from("A request needs a URL")),
+            ProxifyCommand::REQUEST if !has(ProxifyDataType::URL) || !has(ProxifyDataType::METHOD) =>
+                Err(String::from("A REQUEST needs a URL and a method")),
+            _ => Ok(self.data),
         }
-        Spam!("**** loop, done");
-        Ok(tlvs)
     }
 }
+
+/* The reply to a client command. The status tells how the command went, the
+   proxy id which proxy served it (if any) and the TLVs carry the payload: for
+   requests a STATUS TLV with the upstream HTTP status, HEADER TLVs and the
+   body in DATA TLVs, on failure an ERROR TLV with a message.
+
+   Wire format:
+   | session | status | request id (u32 BE) | proxy id (u16 BE, 0xFFFF = none) | TLVs | */
+#[derive(Debug, PartialEq)]
+pub struct ProxifyResponse {
+    pub session: u8,
+    pub status: ProxifyStatus,
+    /* The request_id of the ProxifyData this is the response to */
+    pub request_id: u32,
+    pub proxy_id: Option<u16>,
+    pub data: Vec<(ProxifyDataType, u32, Vec<u8>)>,
+}
+
+impl ProxifyResponse {
+    pub const NO_PROXY_ID: u16 = u16::MAX;
+
+    pub fn new(session: u8, status: ProxifyStatus, proxy_id: Option<u16>) -> Self {
+        ProxifyResponse {
+            session,
+            status,
+            request_id: 0,
+            proxy_id,
+            data: Vec::new(),
+        }
+    }
+
+    /* A response carrying only an error message */
+    pub fn error(session: u8, status: ProxifyStatus, proxy_id: Option<u16>, msg: &str) -> Self {
+        let mut response = Self::new(session, status, proxy_id);
+        response.add_tlv(ProxifyDataType::ERROR, msg.as_bytes());
+        response
+    }
+
+    /* See ProxifyData::add_tlv */
+    pub fn add_tlv(&mut self, tlv_type: ProxifyDataType, value: &[u8]) {
+        push_tlv(&mut self.data, tlv_type, value);
+    }
+
+    /* Returns the upstream HTTP status code from the STATUS TLV, if any */
+    pub fn get_http_status(&self) -> Option<u16> {
+        self.data.iter()
+            .find(|(t, _, v)| *t == ProxifyDataType::STATUS && v.len() == 2)
+            .map(|(_, _, v)| u16::from_be_bytes([v[0], v[1]]))
+    }
+
+    /* Returns all HEADER TLVs, one header line each */
+    pub fn get_headers(&self) -> Vec<String> {
+        self.data.iter()
+            .filter(|(t, _, _)| *t == ProxifyDataType::HEADER)
+            .map(|(_, _, v)| String::from_utf8_lossy(v).to_string())
+            .collect()
+    }
+
+    /* Returns the concatenation of all DATA TLVs */
+    pub fn get_body(&self) -> Vec<u8> {
+        self.data.iter()
+            .filter(|(t, _, _)| *t == ProxifyDataType::DATA)
+            .flat_map(|(_, _, v)| v.iter().copied())
+            .collect()
+    }
+
+    /* Returns the first ERROR TLV, if any */
+    pub fn get_error(&self) -> Option<String> {
+        self.data.iter()
+            .find(|(t, _, _)| *t == ProxifyDataType::ERROR)
+            .map(|(_, _, v)| String::from_utf8_lossy(v).to_string())
+    }
+
+    /* The size marshal_bytes() would produce, without marshalling */
+    pub fn marshalled_len(&self) -> usize {
+        8 + self.data.iter().map(|(_, _, v)| TLV_HEADER_LEN + v.len()).sum::<usize>()
+    }
+
+    pub fn marshal_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec!(self.session, self.status as u8);
+        bytes.extend_from_slice(&self.request_id.to_be_bytes());
+        bytes.extend_from_slice(&self.proxy_id.unwrap_or(Self::NO_PROXY_ID).to_be_bytes());
+        marshal_tlvs(&self.data, &mut bytes);
+        bytes
+    }
+
+    pub fn unmarshal_bytes(data: &[u8]) -> Result<Self, ProxifyDataError> {
+        if data.len() < 8 {
+            return Err(ProxifyDataError::TruncatedHeader);
+        }
+        let session = data[0];
+        let status: ProxifyStatus = match data[1].try_into() {
+            Ok(enum_val) => enum_val,
+            Err(_) => return Err(ProxifyDataError::UnknownStatus(data[1])),
+        };
+        let request_id = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let proxy_id = match u16::from_be_bytes([data[6], data[7]]) {
+            Self::NO_PROXY_ID => None,
+            id => Some(id),
+        };
+        let parsed_data = parse_tlvs(&data[8..])?;
+
+        Ok(ProxifyResponse {
+            session,
+            status,
+            request_id,
+            proxy_id,
+            data: parsed_data,
+        })
+    }
+}
+
+/* Type (1 byte) and length (4 bytes, big-endian) */
+const TLV_HEADER_LEN: usize = 5;
+
+fn push_tlv(tlvs: &mut Vec<(ProxifyDataType, u32, Vec<u8>)>, tlv_type: ProxifyDataType, value: &[u8]) {
+    for chunk in value.chunks(ProxifyData::MAX_TLV_LENGTH) {
+        tlvs.push((tlv_type, chunk.len() as u32, chunk.to_vec()));
+    }
+}
+
+/* The length is always taken from the value itself so a hand-assembled
+   TLV with a wrong length field can not corrupt the stream */
+fn marshal_tlvs(tlvs: &[(ProxifyDataType, u32, Vec<u8>)], bytes: &mut Vec<u8>) {
+    for (tlv_type, _, tlv_value) in tlvs {
+        bytes.push(*tlv_type as u8);
+        bytes.extend_from_slice(&(tlv_value.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(tlv_value);
+    }
+}
+
+fn parse_tlvs(data: &[u8]) -> Result<Vec<(ProxifyDataType, u32, Vec<u8>)>, ProxifyDataError> {
+    let mut tlvs: Vec<(ProxifyDataType, u32, Vec<u8>)> = Vec::new();
+    let mut begin = 0;
+    let end = data.len();
+
+    while begin < end {
+        Spam!("*** loop, begin at {}, end at {}", begin, end);
+        if end - begin < TLV_HEADER_LEN {
+            return Err(ProxifyDataError::TrailingGarbage(end - begin));
+        }
+
+        Spam!("*** loop, try_into {}", data[begin]);
+        let tlv_type: ProxifyDataType = match data[begin].try_into() {
+            Ok(enum_val) => enum_val,
+            Err(_) => return Err(ProxifyDataError::UnknownTlvType(data[begin])),
+        };
+
+        let tlv_length: u32 = u32::from_be_bytes([data[begin + 1],
+                                                  data[begin + 2],
+                                                  data[begin + 3],
+                                                  data[begin + 4]]);
+        Spam!("*** loop, tlv_length {}", tlv_length);
+
+        let slice_begin = begin + TLV_HEADER_LEN;
+        let available = end - slice_begin;
+        if tlv_length as usize > available {
+            return Err(ProxifyDataError::LengthOverflow {
+                needed: tlv_length as usize,
+                available,
+            });
+        }
+        let slice_end = slice_begin + tlv_length as usize;
+
+        let mut tlv_value: Vec<u8> = Vec::new();
+        tlv_value.extend_from_slice(&data[slice_begin..slice_end]);
+        tlvs.push((tlv_type, tlv_length, tlv_value));
+
+        // For every loop we move one TLV forward (T, L and D[size])
+        begin = slice_end;
+    }
+    Spam!("**** loop, done");
+    Ok(tlvs)
+}
@@ -0,0 +1,107 @@
+use std::io::{self, Read, Write};
+
+/* Sent by the client right after connecting and echoed back by the daemon */
+pub const MAGIC_BYTES: [u8; 4] = [ 0xAB, 0xBA, 0xAB, 0xBA ];
+
+/* Every message on the wire (after the magic bytes handshake) is wrapped in
+   a frame:
+
+   | magic (2 bytes) | version (1 byte) | length (4 bytes, BE) | payload |
+
+   where length is the size of the payload only. */
+pub const FRAME_MAGIC: [u8; 2] = [ 0xAB, 0xBA ];
+/* Version 2 moved to 4 byte TLV lengths, version 3 added request ids */
+pub const PROTOCOL_VERSION: u8 = 3;
+pub const FRAME_HEADER_LEN: usize = 7;
+/* Refuse frames larger than this instead of buffering them forever */
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/* Wrap a payload in a frame header */
+pub fn frame_bytes(payload: &[u8]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&FRAME_MAGIC);
+    bytes.push(PROTOCOL_VERSION);
+    bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&frame_bytes(payload))
+}
+
+/* Check a frame header and return the payload length it announces */
+fn parse_header(header: &[u8]) -> Result<usize, String> {
+    if header[0..2] != FRAME_MAGIC {
+        return Err(String::from("Invalid frame magic"));
+    }
+    if header[2] != PROTOCOL_VERSION {
+        return Err(format!("Unsupported protocol version {} (expected {})",
+                           header[2],
+                           PROTOCOL_VERSION));
+    }
+    let length = u32::from_be_bytes([header[3], header[4], header[5], header[6]]) as usize;
+    if length > MAX_FRAME_LEN {
+        return Err(format!("Frame too large ({} bytes, max {})", length, MAX_FRAME_LEN));
+    }
+    Ok(length)
+}
+
+/* Blocking read of exactly one frame, returns its payload. Meant for
+   clients that wait for one reply at a time. */
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0_u8; FRAME_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let length = parse_header(&header)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut payload = vec![0_u8; length];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/* Accumulates bytes as they arrive from a socket and hands out complete
+   frames. A single read may contain a partial frame, exactly one frame or
+   several frames, so bytes are kept until a whole frame is available. */
+pub struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        FrameReader { buf: Vec::new() }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /* Take a fixed number of raw (unframed) bytes, e.g. the magic bytes of
+       the handshake. Returns None until enough bytes have arrived. */
+    pub fn take_bytes(&mut self, count: usize) -> Option<Vec<u8>> {
+        if self.buf.len() < count {
+            return None;
+        }
+        Some(self.buf.drain(0..count).collect())
+    }
+
+    /* Returns the payload of the next complete frame, None if more data is
+       needed or an error if the buffered data is not a valid frame */
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if self.buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let length = parse_header(&self.buf[0..FRAME_HEADER_LEN])?;
+        if self.buf.len() < FRAME_HEADER_LEN + length {
+            return Ok(None);
+        }
+        let payload = self.buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + length].to_vec();
+        self.buf.drain(0..FRAME_HEADER_LEN + length);
+        Ok(Some(payload))
+    }
+}
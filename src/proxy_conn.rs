@@ -1,15 +1,16 @@
-use curl::easy::{Easy, Handler, List, WriteError};
-use std::io::Read;
-use std::sync::atomic::{AtomicBool, Ordering};
+use curl::easy::{Easy2, Handler, List, ReadError, WriteError};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::str::FromStr;
 use std::fmt;
 
 use crate::common::VERBOSITY;
 use crate::common::verbose_print::VerbosityLevel;
-use crate::{Error, Warn, Inform, Detail, Spam};
+use crate::{Detail, Spam};
+use crate::transfer_driver::TransferDriver;
+use crate::proxify_frame::MAX_FRAME_LEN;
+use crate::proxy_stats::{ProxyStats, TransferSample};
 
 pub enum ProxyConnProtocol {
     HTTP,
@@ -67,6 +68,50 @@ impl fmt::Display for ProxyConnProtocol {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyConnMethod {
+    GET,
+    POST,
+    PUT,
+    PATCH,
+    DELETE,
+    HEAD,
+    OPTIONS,
+}
+
+impl FromStr for ProxyConnMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_uppercase().as_str() {
+            "GET" => Ok(ProxyConnMethod::GET),
+            "POST" => Ok(ProxyConnMethod::POST),
+            "PUT" => Ok(ProxyConnMethod::PUT),
+            "PATCH" => Ok(ProxyConnMethod::PATCH),
+            "DELETE" => Ok(ProxyConnMethod::DELETE),
+            "HEAD" => Ok(ProxyConnMethod::HEAD),
+            "OPTIONS" => Ok(ProxyConnMethod::OPTIONS),
+            _ => Err(format!("Unsupported HTTP method '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for ProxyConnMethod {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str(
+            match self {
+                ProxyConnMethod::GET => "GET",
+                ProxyConnMethod::POST => "POST",
+                ProxyConnMethod::PUT => "PUT",
+                ProxyConnMethod::PATCH => "PATCH",
+                ProxyConnMethod::DELETE => "DELETE",
+                ProxyConnMethod::HEAD => "HEAD",
+                ProxyConnMethod::OPTIONS => "OPTIONS",
+            }
+        )
+    }
+}
+
 pub struct ProxyConn {
     id: u16,
     proxy_prot: ProxyConnProtocol,
@@ -74,14 +119,191 @@ pub struct ProxyConn {
     proxy_port: u16,
     proxy_username: Option<String>,
     proxy_password: Option<String>,
-    curl_handle: Easy,
-    recv_buf: Arc<Mutex<Vec<u8>>>,
+    /* Kept between requests so connections to the proxy are reused. It is
+       None while lent to the TransferDriver. */
+    curl_handle: Option<Easy2<ProxyConnCollector>>,
     prepared: bool,
+    /* When the last transfer through the proxy succeeded */
+    last_checked: Option<Instant>,
+    /* Set on an in-use proxy that has not worked for too long, it is
+       checked again before going back to the ready proxies */
+    recheck_due: bool,
+    /* Failed health checks in a row, reset by a passed one */
+    consecutive_failures: u32,
+    total_failures: u64,
+    /* Not to be checked again before this */
+    retry_after: Option<Instant>,
+    quarantined: bool,
+    /* Taken out of rotation by an operator, it is not checked or handed
+       out until enabled again */
+    disabled: bool,
+    /* When the proxy was last handed out to a client */
+    last_used: Option<Instant>,
+    /* Transfers through the proxy, health checks included */
+    stats: ProxyStats,
 }
 
-impl ProxyConn {
-    const PREPARE_URL: &'static str = "https://google.com";
+/* CURLE_PROXY, a SOCKS or HTTPS proxy handshake failed. The curl crate
+   has no helper for it. */
+const CURLE_PROXY: u32 = 97;
+
+/* Why a request through a proxy failed */
+#[derive(Debug)]
+pub enum ProxyConnError {
+    /* The proxy or the upstream did not answer in time */
+    Timeout(String),
+    /* The proxy could not be reached or failed its handshake */
+    Proxy(String),
+    /* The request itself is at fault, e.g. a malformed URL or an invalid
+       header, it never got to the proxy */
+    Request(String),
+    /* Any other failure, from setting up the handle to the transfer itself */
+    Failed(String),
+}
+
+impl ProxyConnError {
+    /* Whether the failure says something about the proxy, as opposed to
+       the request or the upstream */
+    pub fn is_proxy_fault(&self) -> bool {
+        matches!(self, ProxyConnError::Timeout(_) | ProxyConnError::Proxy(_))
+    }
+}
+
+impl fmt::Display for ProxyConnError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyConnError::Timeout(e) => write!(fmt, "Timeout: {}", e),
+            ProxyConnError::Proxy(e) => write!(fmt, "Proxy failed: {}", e),
+            ProxyConnError::Request(e) |
+            ProxyConnError::Failed(e) => fmt.write_str(e),
+        }
+    }
+}
+
+impl From<curl::Error> for ProxyConnError {
+    fn from(e: curl::Error) -> Self {
+        if e.is_operation_timedout() {
+            ProxyConnError::Timeout(e.to_string())
+        } else if e.is_couldnt_connect() || e.is_couldnt_resolve_proxy() || e.code() == CURLE_PROXY {
+            ProxyConnError::Proxy(e.to_string())
+        } else if e.is_url_malformed() || e.is_unsupported_protocol() {
+            ProxyConnError::Request(e.to_string())
+        } else {
+            ProxyConnError::Failed(e.to_string())
+        }
+    }
+}
+
+/* Responses are collected up to this many bytes, headers included. A larger
+   one could not be sent to the client in a frame anyway. */
+pub const MAX_RESPONSE_LEN: usize = MAX_FRAME_LEN;
+
+/* Feeds the request body to cURL and collects the response as it arrives */
+#[derive(Default)]
+pub struct ProxyConnCollector {
+    send_data: Vec<u8>,
+    send_pos: usize,
+    headers: Vec<String>,
+    body: Vec<u8>,
+    /* Bytes of headers and body received so far */
+    received: usize,
+    /* Set when the response went over MAX_RESPONSE_LEN, the transfer is
+       aborted then */
+    too_large: bool,
+}
+
+impl ProxyConnCollector {
+    /* Count received bytes, false once there are too many */
+    fn receive(&mut self, size: usize) -> bool {
+        self.received += size;
+        self.too_large = self.received > MAX_RESPONSE_LEN;
+        !self.too_large
+    }
+}
+
+impl Handler for ProxyConnCollector {
+    fn read(&mut self, into: &mut [u8]) -> Result<usize, ReadError> {
+        let remaining = &self.send_data[self.send_pos..];
+        let size = remaining.len().min(into.len());
+        into[..size].copy_from_slice(&remaining[..size]);
+        self.send_pos += size;
+        Ok(size)
+    }
+
+    /* When going through a proxy there can be several header blocks (e.g.
+       the CONNECT reply), only keep the last one */
+    fn header(&mut self, header: &[u8]) -> bool {
+        if !self.receive(header.len()) {
+            return false;
+        }
+        let line = String::from_utf8_lossy(header).trim_end().to_string();
+        if line.starts_with("HTTP/") {
+            self.headers.clear();
+        } else if !line.is_empty() {
+            self.headers.push(line);
+        }
+        true
+    }
+
+    /* Taking fewer bytes than offered aborts the transfer */
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        if !self.receive(data.len()) {
+            return Ok(0);
+        }
+        self.body.extend_from_slice(data);
+        Ok(data.len())
+    }
+}
+
+/* The upstream response of a request made through a proxy */
+pub struct ProxyConnResponse {
+    pub status_code: u32,
+    pub headers: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+/* What a health check requests through a proxy and what it expects back */
+pub struct HealthCheck {
+    pub url: String,
+    pub method: ProxyConnMethod,
+    /* Seconds for the whole check, connecting included */
+    pub timeout_sec: u16,
+    /* Accepted status codes as inclusive ranges, empty accepts any */
+    pub expected_status: Vec<(u32, u32)>,
+    /* If set the response body must contain it */
+    pub body_contains: Option<String>,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            url: String::from("https://google.com"),
+            method: ProxyConnMethod::GET,
+            timeout_sec: 5,
+            expected_status: Vec::new(),
+            body_contains: None,
+        }
+    }
+}
 
+impl HealthCheck {
+    /* Whether a response passes the check, with the reason if it does not */
+    pub fn verify(&self, resp: &ProxyConnResponse) -> Result<(), String> {
+        if !self.expected_status.is_empty() &&
+           !self.expected_status.iter().any(|(low, high)| (*low..=*high).contains(&resp.status_code)) {
+            return Err(format!("Unexpected status code {}", resp.status_code));
+        }
+        if let Some(needle) = &self.body_contains {
+            let needle = needle.as_bytes();
+            if !needle.is_empty() && !resp.body.windows(needle.len()).any(|w| w == needle) {
+                return Err(format!("Body does not contain '{}'", String::from_utf8_lossy(needle)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProxyConn {
     pub fn new(id: u16,
                prot: ProxyConnProtocol,
                addr: String,
@@ -91,15 +313,23 @@ impl ProxyConn {
         Self::init_curl();
 
         Self {
-            id: id,
+            id,
             proxy_prot: prot,
             proxy_addr: addr,
             proxy_port: port,
             proxy_username: username,
             proxy_password: password,
-            curl_handle: Easy::new(),
-            recv_buf: Arc::new(Mutex::new(Vec::new())),
-            prepared: false
+            curl_handle: None,
+            prepared: false,
+            last_checked: None,
+            recheck_due: false,
+            consecutive_failures: 0,
+            total_failures: 0,
+            retry_after: None,
+            quarantined: false,
+            disabled: false,
+            last_used: None,
+            stats: ProxyStats::default(),
         }
     }
 
@@ -107,10 +337,111 @@ impl ProxyConn {
         self.id
     }
 
+    pub fn get_protocol(&self) -> &ProxyConnProtocol {
+        &self.proxy_prot
+    }
+
+    /* The proxy URL without credentials, for reporting */
+    pub fn get_address(&self) -> String {
+        format!("{}://{}:{}", self.proxy_prot, self.proxy_addr, self.proxy_port)
+    }
+
     pub fn is_prepared(&self) -> bool {
         self.prepared
     }
 
+    /* Mark the proxy as needing to be prepared again, e.g. after it failed
+       a client request */
+    pub fn set_unprepared(&mut self) {
+        self.prepared = false;
+    }
+
+    /* True if no transfer through the proxy has succeeded within max_age */
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        match self.last_checked {
+            Some(t) => t.elapsed() >= max_age,
+            None => true,
+        }
+    }
+
+    pub fn is_recheck_due(&self) -> bool {
+        self.recheck_due
+    }
+
+    pub fn set_recheck_due(&mut self) {
+        self.recheck_due = true;
+    }
+
+    pub fn get_consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    pub fn get_total_failures(&self) -> u64 {
+        self.total_failures
+    }
+
+    /* Count a failed health check and hold off the next one for backoff */
+    pub fn record_failure(&mut self, backoff: Duration) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.total_failures += 1;
+        self.retry_after = Some(Instant::now() + backoff);
+    }
+
+    /* When the backoff after the last failure ends */
+    pub fn get_retry_after(&self) -> Option<Instant> {
+        self.retry_after
+    }
+
+    /* True once the backoff after the last failure has passed */
+    pub fn is_retry_due(&self) -> bool {
+        match self.retry_after {
+            Some(t) => Instant::now() >= t,
+            None => true,
+        }
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined
+    }
+
+    pub fn set_quarantined(&mut self) {
+        self.quarantined = true;
+    }
+
+    /* Forget the failures so far, e.g. when an operator takes the proxy
+       out of quarantine */
+    pub fn reset_failures(&mut self) {
+        self.consecutive_failures = 0;
+        self.retry_after = None;
+        self.quarantined = false;
+    }
+
+    /* Skip what is left of the backoff */
+    pub fn set_retry_due(&mut self) {
+        self.retry_after = None;
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+
+    pub fn get_last_used(&self) -> Option<Instant> {
+        self.last_used
+    }
+
+    /* Note that the proxy was handed out to a client */
+    pub fn set_used(&mut self) {
+        self.last_used = Some(Instant::now());
+    }
+
+    pub fn get_stats(&self) -> &ProxyStats {
+        &self.stats
+    }
+
     pub fn init_curl() {
         static CURL_INIT_DONE: AtomicBool = AtomicBool::new(false);
         if !CURL_INIT_DONE.load(Ordering::Relaxed) {
@@ -122,19 +453,37 @@ impl ProxyConn {
         }
     }
 
-    pub fn prepare(&mut self) -> Result<bool, String> {
+    /* Run the health check through the proxy. Returns Ok(false) if it timed
+       out and an error if it failed in any other way. */
+    pub fn prepare(&mut self, check: &HealthCheck) -> Result<bool, String> {
         Spam!("Proxy {} preparing", self.id);
+        self.prepared = false;
+
+        let result = self.begin_transfer(check.method, &check.url, &None, check.timeout_sec, None, None)
+            .and_then(|handle| {
+                let result = handle.perform().map_err(ProxyConnError::from);
+                self.finish_transfer(Some(handle), result, None)
+            });
+        self.check_result(check, result)
+    }
 
-        match self.request_get(&Self::PREPARE_URL.to_string(),
-                               &None,
-                               5,
-                               None) {
-            Ok(_) => Ok(true),
-            Err(e) if e.contains("timeout") => {
-                println!("Failed!!!!");
-                Ok(false)
-            }
-            Err(e) => Err(e),
+    fn check_result(&mut self,
+                    check: &HealthCheck,
+                    result: Result<ProxyConnResponse, ProxyConnError>) -> Result<bool, String> {
+        match result.map(|resp| check.verify(&resp)) {
+            Ok(Ok(_)) => {
+                self.consecutive_failures = 0;
+                self.retry_after = None;
+                Ok(true)
+            },
+            Ok(Err(e)) => {
+                let e = format!("Health check failed: {}", e);
+                self.prepared = false;
+                self.stats.set_last_error(e.clone());
+                Err(e)
+            },
+            Err(ProxyConnError::Timeout(_)) => Ok(false),
+            Err(e) => Err(e.to_string()),
         }
     }
 
@@ -159,80 +508,308 @@ impl ProxyConn {
     }
 
     pub fn request_get(&mut self,
-                       url: &String,
+                       url: &str,
                        headers: &Option<Vec<String>>,
-                       timeout_sec: u16,
-                       send_data: Option<&[u8]>) -> Result<Vec<u8>, String> {
-        Spam!("Sending request using proxy {}", self.id);
+                       timeout_sec: u16) -> Result<ProxyConnResponse, ProxyConnError> {
+        self.request(ProxyConnMethod::GET, url, headers, timeout_sec, None)
+    }
+
+    pub fn request_post(&mut self,
+                        url: &str,
+                        headers: &Option<Vec<String>>,
+                        timeout_sec: u16,
+                        send_data: &[u8]) -> Result<ProxyConnResponse, ProxyConnError> {
+        self.request(ProxyConnMethod::POST, url, headers, timeout_sec, Some(send_data))
+    }
 
-        if let Err(e) = self.curl_handle.url(url) {
-            return Err(format!("Failed to set URL {} for the cURL handler: {}",
-                               url,
-                               e.to_string()));
+    /* Perform a request with any HTTP method through the proxy. If send_data
+       is given it is sent as the request body. */
+    pub fn request(&mut self,
+                   method: ProxyConnMethod,
+                   url: &str,
+                   headers: &Option<Vec<String>>,
+                   timeout_sec: u16,
+                   send_data: Option<&[u8]>) -> Result<ProxyConnResponse, ProxyConnError> {
+        self.request_with_cookies(method, url, headers, timeout_sec, send_data, None)
+    }
+
+    /* Like request() but with a cookie jar (cURL/Netscape cookie lines). The
+       cookies in the jar are sent with the request and the jar is replaced
+       with the cookies known after the response, so it can be kept between
+       requests. The cookies are wiped from the handle afterwards so they do
+       not leak to the next user of the proxy. */
+    pub fn request_with_cookies(&mut self,
+                                method: ProxyConnMethod,
+                                url: &str,
+                                headers: &Option<Vec<String>>,
+                                timeout_sec: u16,
+                                send_data: Option<&[u8]>,
+                                cookie_jar: Option<&mut Vec<String>>) -> Result<ProxyConnResponse, ProxyConnError> {
+        let handle = self.begin_transfer(method,
+                                         url,
+                                         headers,
+                                         timeout_sec,
+                                         send_data,
+                                         cookie_jar.as_deref().map(Vec::as_slice))?;
+        let result = handle.perform().map_err(ProxyConnError::from);
+        self.finish_transfer(Some(handle), result, cookie_jar)
+    }
+
+    /* The non-blocking counterpart of request_with_cookies(). The transfer
+       runs on the driver's multi handle and the proxy is only locked while
+       setting it up and collecting the result, so the caller must make sure
+       nobody else uses the proxy in between (i.e. it is in use). */
+    #[allow(clippy::too_many_arguments)]
+    pub async fn request_async(proxy: &Arc<Mutex<ProxyConn>>,
+                               driver: &TransferDriver,
+                               method: ProxyConnMethod,
+                               url: &str,
+                               headers: &Option<Vec<String>>,
+                               timeout_sec: u16,
+                               send_data: Option<&[u8]>,
+                               cookie_jar: Option<&mut Vec<String>>) -> Result<ProxyConnResponse, ProxyConnError> {
+        let handle = proxy.lock().unwrap().begin_transfer(method,
+                                                          url,
+                                                          headers,
+                                                          timeout_sec,
+                                                          send_data,
+                                                          cookie_jar.as_deref().map(Vec::as_slice))?;
+        let (handle, result) = driver.perform(handle).await;
+        proxy.lock().unwrap().finish_transfer(handle, result, cookie_jar)
+    }
+
+    /* The non-blocking counterpart of prepare() */
+    pub async fn prepare_async(proxy: &Arc<Mutex<ProxyConn>>,
+                               driver: &TransferDriver,
+                               check: &HealthCheck) -> Result<bool, String> {
+        let handle = {
+            let mut p = proxy.lock().unwrap();
+            Spam!("Proxy {} preparing", p.id);
+            p.prepared = false;
+            p.begin_transfer(check.method, &check.url, &None, check.timeout_sec, None, None)
+        };
+
+        let result = match handle {
+            Ok(handle) => {
+                let (handle, result) = driver.perform(handle).await;
+                proxy.lock().unwrap().finish_transfer(handle, result, None)
+            },
+            Err(e) => Err(e),
+        };
+        proxy.lock().unwrap().check_result(check, result)
+    }
+
+    /* Take the handle (creating it on first use) and set it up for a
+       request. On failure the handle is kept for the next request. */
+    fn begin_transfer(&mut self,
+                      method: ProxyConnMethod,
+                      url: &str,
+                      headers: &Option<Vec<String>>,
+                      timeout_sec: u16,
+                      send_data: Option<&[u8]>,
+                      cookie_jar: Option<&[String]>) -> Result<Easy2<ProxyConnCollector>, ProxyConnError> {
+        let mut handle = self.curl_handle.take()
+            .unwrap_or_else(|| Easy2::new(ProxyConnCollector::default()));
+
+        /* The handle is reused between requests, start from a clean slate */
+        handle.reset();
+        *handle.get_mut() = ProxyConnCollector {
+            send_data: send_data.map(|d| d.to_vec()).unwrap_or_default(),
+            ..Default::default()
+        };
+
+        let result = match cookie_jar {
+            Some(jar) => Self::load_cookies(&mut handle, jar)
+                .and_then(|_| self.setup_request(&mut handle, method, url, headers, timeout_sec, send_data)),
+            None => self.setup_request(&mut handle, method, url, headers, timeout_sec, send_data),
+        };
+        match result {
+            Ok(_) => Ok(handle),
+            Err(e) => {
+                let _ = handle.cookie_list("ALL");
+                self.curl_handle = Some(handle);
+                Err(e)
+            },
         }
+    }
 
-        /* If headers are set, apply them to the handle */
-        if let Some(hdrs) = headers {
-            let mut list = List::new();
-            for h in hdrs {
-                list.append(h).unwrap();
+    /* Collect the response of a performed transfer and put the handle back.
+       The handle is missing if the transfer never got started. */
+    fn finish_transfer(&mut self,
+                       handle: Option<Easy2<ProxyConnCollector>>,
+                       result: Result<(), ProxyConnError>,
+                       cookie_jar: Option<&mut Vec<String>>) -> Result<ProxyConnResponse, ProxyConnError> {
+        let mut handle = match handle {
+            Some(h) => h,
+            None => {
+                let e = result.err().unwrap_or_else(|| {
+                    ProxyConnError::Failed(String::from("The transfer lost its cURL handle"))
+                });
+                self.stats.record(TransferSample {
+                    at: Instant::now(),
+                    success: false,
+                    connect_time: Duration::ZERO,
+                    total_time: Duration::ZERO,
+                    bytes_sent: 0,
+                    bytes_received: 0,
+                }, Some(e.to_string()));
+                return Err(e);
+            },
+        };
+        let collector = std::mem::take(handle.get_mut());
+
+        let result = match result {
+            Err(_) if collector.too_large => Err(ProxyConnError::Failed(
+                format!("The response is larger than {} bytes", MAX_RESPONSE_LEN))),
+            result => result,
+        };
+        let response = result.and_then(|_| {
+            let status_code = match handle.response_code() {
+                Ok(c) => c,
+                Err(e) => return Err(ProxyConnError::Failed(format!("Failed to get the response code: {}", e))),
+            };
+            if let Some(jar) = cookie_jar {
+                Self::store_cookies(&mut handle, jar)?;
             }
-            self.curl_handle.http_headers(list).unwrap();
+            Ok(ProxyConnResponse {
+                status_code,
+                headers: collector.headers,
+                body: collector.body,
+            })
+        });
+        let _ = handle.cookie_list("ALL");
+        /* A request that never got to the proxy says nothing about it */
+        if !matches!(response, Err(ProxyConnError::Request(_))) {
+            self.stats.record(TransferSample {
+                at: Instant::now(),
+                success: response.is_ok(),
+                connect_time: handle.connect_time().unwrap_or_default(),
+                total_time: handle.total_time().unwrap_or_default(),
+                bytes_sent: handle.upload_size().unwrap_or_default() as u64,
+                bytes_received: handle.download_size().unwrap_or_default() as u64,
+            }, response.as_ref().err().map(|e| e.to_string()));
         }
+        self.curl_handle = Some(handle);
 
-        let mut buf = Vec::new();
-
-        /* Set the timeout for the connect operation */
-        self.curl_handle.connect_timeout(Duration::from_secs(timeout_sec.into())).unwrap();
+        if let Ok(resp) = &response {
+            self.prepared = true;
+            self.last_checked = Some(Instant::now());
+            self.recheck_due = false;
+            Spam!("Data received:\n {}", String::from_utf8_lossy(&resp.body));
+        }
+        response
+    }
 
-        /* Set the poroxy to be used */
-        let proxy_url = self.generate_proxy_url();
-        if let Err(e) = self.curl_handle.proxy(proxy_url.as_str()) {
-                return Err(format!("Failed to setr proxy: {}", e.to_string()));
+    fn load_cookies(handle: &mut Easy2<ProxyConnCollector>, jar: &[String]) -> Result<(), ProxyConnError> {
+        /* An empty file name only enables the cookie engine */
+        if let Err(e) = handle.cookie_file("") {
+            return Err(ProxyConnError::Failed(format!("Failed to enable cookies: {}", e)));
         }
+        for cookie in jar {
+            if let Err(e) = handle.cookie_list(cookie) {
+                return Err(ProxyConnError::Failed(format!("Failed to set cookie: {}", e)));
+            }
+        }
+        Ok(())
+    }
 
-        Detail!("Using proxy url '{}'", proxy_url);
+    fn store_cookies(handle: &mut Easy2<ProxyConnCollector>, jar: &mut Vec<String>) -> Result<(), ProxyConnError> {
+        let cookies = match handle.cookies() {
+            Ok(c) => c,
+            Err(e) => return Err(ProxyConnError::Failed(format!("Failed to get cookies: {}", e))),
+        };
+        jar.clear();
+        for cookie in cookies.iter() {
+            jar.push(String::from_utf8_lossy(cookie).to_string());
+        }
+        Ok(())
+    }
 
-        let mut transfer = self.curl_handle.transfer();
+    fn setup_request(&self,
+                     handle: &mut Easy2<ProxyConnCollector>,
+                     method: ProxyConnMethod,
+                     url: &str,
+                     headers: &Option<Vec<String>>,
+                     timeout_sec: u16,
+                     send_data: Option<&[u8]>) -> Result<(), ProxyConnError> {
+        Spam!("Sending {} request using proxy {}", method, self.id);
+
+        if let Err(e) = handle.url(url) {
+            return Err(ProxyConnError::Request(format!("Failed to set URL {} for the cURL handler: {}",
+                                                       url,
+                                                       e)));
+        }
 
-        /* Set the sending closure */
-        if let Some(mut snd_data) = send_data {
-            Spam!("Data to send:\n {}", str::from_utf8(&snd_data).unwrap());
-            if let Err(e) = transfer.read_function(move |into| {
-                Ok(snd_data.read(into).unwrap())
-            }) {
-                return Err(format!("Failed to set write_function: {}", e.to_string()));
+        /* If headers are set, apply them to the handle. They come from the
+           client, so e.g. a NUL byte in one must not panic. */
+        if let Some(hdrs) = headers {
+            let mut list = List::new();
+            for h in hdrs {
+                if let Err(e) = list.append(h) {
+                    return Err(ProxyConnError::Request(format!("Invalid header '{}': {}", h.escape_debug(), e)));
+                }
+            }
+            if let Err(e) = handle.http_headers(list) {
+                return Err(ProxyConnError::Request(format!("Failed to set the headers: {}", e)));
             }
         }
 
-        /* Set the receiving closure */
-        if let Err(e) = transfer.write_function(|recv_data| {
-            buf.extend_from_slice(&recv_data);
-            Ok(recv_data.len())
-        }) {
-            return Err(format!("Failed to set write_function: {}", e.to_string()));
+        /* The whole transfer, connecting included, must be done in time. An
+           upstream that stalls after connecting would hold the proxy
+           forever otherwise. */
+        if let Err(e) = handle.timeout(Duration::from_secs(timeout_sec.into())) {
+            return Err(ProxyConnError::Failed(format!("Failed to set the timeout: {}", e)));
         }
 
-        /* Do the request */
-        if let Err(e) = transfer.perform() {
-            return Err(e.to_string());
+        /* Set the poroxy to be used */
+        let proxy_url = self.generate_proxy_url();
+        if let Err(e) = handle.proxy(proxy_url.as_str()) {
+                return Err(ProxyConnError::Failed(format!("Failed to setr proxy: {}", e)));
         }
 
-        /* We need to drop the transfer to let go of the borrowed buffer */
-        drop(transfer);
+        Detail!("Using proxy url '{}'", proxy_url);
 
-        self.prepared = true;
-        Spam!("Data received:\n {}", str::from_utf8(&buf).unwrap());
+        /* HEAD must not wait for a body, it does not send one either. A POST
+           always has one, if only an empty one, cURL would send a GET
+           otherwise. */
+        let send_data = match method {
+            ProxyConnMethod::HEAD => None,
+            ProxyConnMethod::POST => Some(send_data.unwrap_or_default()),
+            _ => send_data,
+        };
+
+        /* Let cURL know the size of the body up front so it sends a
+           Content-Length instead of a chunked upload */
+        if let Some(snd_data) = send_data {
+            Spam!("Data to send:\n {}", String::from_utf8_lossy(snd_data));
+            if let Err(e) = handle.post(true) {
+                return Err(ProxyConnError::Failed(format!("Failed to set the POST method: {}", e)));
+            }
+            if let Err(e) = handle.post_field_size(snd_data.len() as u64) {
+                return Err(ProxyConnError::Failed(format!("Failed to set the POST body size: {}", e)));
+            }
+        }
 
-        Ok(buf)
+        /* POST and a GET without a body are covered by the settings above.
+           Sending a body turns the request into a POST, so everything else,
+           a GET with a body included, overrides the request verb. */
+        let method_res = match method {
+            ProxyConnMethod::POST => Ok(()),
+            ProxyConnMethod::GET if send_data.is_none() => Ok(()),
+            ProxyConnMethod::HEAD => handle.nobody(true),
+            _ => handle.custom_request(&method.to_string()),
+        };
+        if let Err(e) = method_res {
+            return Err(ProxyConnError::Failed(format!("Failed to set the {} method: {}", method, e)));
+        }
+        Ok(())
     }
 
-    pub fn request_get_as_string(&mut self, url: &String,
-                                 headers: &Option<Vec<String>>,
-                                 send_data: Option<&[u8]>) -> Result<String, String> {
-        match self.request_get(url, headers, 10, send_data) {
-            Ok(data) => Ok(String::from_utf8_lossy(&data).to_string()),
-            Err(e) => Err(e),
+    pub fn request_get_as_string(&mut self, url: &str,
+                                 headers: &Option<Vec<String>>) -> Result<String, String> {
+        match self.request_get(url, headers, 10) {
+            Ok(resp) => Ok(String::from_utf8_lossy(&resp.body).to_string()),
+            Err(e) => Err(e.to_string()),
         }
     }
 }
@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/* How one transfer through a proxy went */
+#[derive(Debug, Clone)]
+pub struct TransferSample {
+    pub at: Instant,
+    pub success: bool,
+    /* Time until the connection to the proxy was up */
+    pub connect_time: Duration,
+    pub total_time: Duration,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/* Transfer statistics of a proxy. The averages and rates are taken over the
+   last WINDOW transfers so that they follow how the proxy does now, the
+   totals count every transfer since the daemon started. */
+#[derive(Debug, Default)]
+pub struct ProxyStats {
+    window: VecDeque<TransferSample>,
+    total_successes: u64,
+    total_failures: u64,
+    total_bytes_sent: u64,
+    total_bytes_received: u64,
+    last_error: Option<(Instant, String)>,
+}
+
+impl ProxyStats {
+    /* Number of recent transfers the averages and rates are taken over */
+    pub const WINDOW: usize = 100;
+    /* Recent transfers needed before the proxy can be judged as failing */
+    const MIN_SAMPLES: usize = 5;
+
+    pub fn record(&mut self, sample: TransferSample, error: Option<String>) {
+        if sample.success {
+            self.total_successes += 1;
+        } else {
+            self.total_failures += 1;
+        }
+        self.total_bytes_sent += sample.bytes_sent;
+        self.total_bytes_received += sample.bytes_received;
+        if let Some(e) = error {
+            self.last_error = Some((sample.at, e));
+        }
+
+        if self.window.len() == Self::WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back(sample);
+    }
+
+    /* For failures that are not a failed transfer, e.g. a health check
+       that got the wrong answer */
+    pub fn set_last_error(&mut self, error: String) {
+        self.last_error = Some((Instant::now(), error));
+    }
+
+    pub fn get_total_successes(&self) -> u64 {
+        self.total_successes
+    }
+
+    pub fn get_total_failures(&self) -> u64 {
+        self.total_failures
+    }
+
+    pub fn get_total_bytes_sent(&self) -> u64 {
+        self.total_bytes_sent
+    }
+
+    pub fn get_total_bytes_received(&self) -> u64 {
+        self.total_bytes_received
+    }
+
+    /* The last error and when it happened */
+    pub fn get_last_error(&self) -> Option<&(Instant, String)> {
+        self.last_error.as_ref()
+    }
+
+    /* Number of transfers in the window and how many of them succeeded */
+    pub fn get_recent_counts(&self) -> (usize, usize) {
+        (self.window.len(), self.window.iter().filter(|s| s.success).count())
+    }
+
+    /* Share of the recent transfers that succeeded. A proxy without any
+       counts as half working rather than perfect or useless. */
+    pub fn get_success_rate(&self) -> f64 {
+        let (total, successes) = self.get_recent_counts();
+        (successes as f64 + 1.0) / (total as f64 + 2.0)
+    }
+
+    /* Average connect time of the recent successful transfers */
+    pub fn get_avg_connect_time(&self) -> Option<Duration> {
+        self.average(|s| s.connect_time)
+    }
+
+    /* Average total time of the recent successful transfers */
+    pub fn get_avg_total_time(&self) -> Option<Duration> {
+        self.average(|s| s.total_time)
+    }
+
+    /* True if enough recent transfers failed to no longer trust the proxy */
+    pub fn is_failing(&self) -> bool {
+        let (total, successes) = self.get_recent_counts();
+        total >= Self::MIN_SAMPLES && successes * 2 < total
+    }
+
+    fn average(&self, value: impl Fn(&TransferSample) -> Duration) -> Option<Duration> {
+        let values: Vec<Duration> = self.window.iter()
+            .filter(|s| s.success)
+            .map(value)
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        Some(values.iter().sum::<Duration>() / values.len() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(success: bool, millis: u64) -> TransferSample {
+        TransferSample {
+            at: Instant::now(),
+            success,
+            connect_time: Duration::from_millis(millis / 2),
+            total_time: Duration::from_millis(millis),
+            bytes_sent: 10,
+            bytes_received: 100,
+        }
+    }
+
+    fn stats(outcomes: &[bool]) -> ProxyStats {
+        let mut stats = ProxyStats::default();
+        for success in outcomes {
+            stats.record(sample(*success, 100), None);
+        }
+        stats
+    }
+
+    #[test]
+    fn window_keeps_the_last_transfers() {
+        let mut stats = stats(&[false; ProxyStats::WINDOW]);
+        for _ in 0..10 {
+            stats.record(sample(true, 100), None);
+        }
+        assert_eq!(stats.get_recent_counts(), (ProxyStats::WINDOW, 10));
+        /* The totals count everything */
+        assert_eq!(stats.get_total_failures(), ProxyStats::WINDOW as u64);
+        assert_eq!(stats.get_total_successes(), 10);
+        assert_eq!(stats.get_total_bytes_sent(), 10 * (ProxyStats::WINDOW as u64 + 10));
+        assert_eq!(stats.get_total_bytes_received(), 100 * (ProxyStats::WINDOW as u64 + 10));
+    }
+
+    #[test]
+    fn success_rate_starts_at_one_half() {
+        assert_eq!(stats(&[]).get_success_rate(), 0.5);
+        assert_eq!(stats(&[true]).get_success_rate(), 2.0 / 3.0);
+        assert_eq!(stats(&[false]).get_success_rate(), 1.0 / 3.0);
+        assert_eq!(stats(&[true, true, false, false]).get_success_rate(), 0.5);
+        assert_eq!(stats(&[true; 8]).get_success_rate(), 0.9);
+    }
+
+    #[test]
+    fn failing_needs_enough_samples_and_a_majority() {
+        assert!(!stats(&[false; 4]).is_failing());
+        assert!(stats(&[false; 5]).is_failing());
+        assert!(stats(&[true, true, false, false, false]).is_failing());
+        assert!(!stats(&[true, true, true, false, false]).is_failing());
+        /* Exactly half is not a majority */
+        assert!(!stats(&[true, true, true, false, false, false]).is_failing());
+    }
+
+    #[test]
+    fn averages_only_count_successes() {
+        let mut stats = ProxyStats::default();
+        assert_eq!(stats.get_avg_total_time(), None);
+        stats.record(sample(false, 5000), None);
+        assert_eq!(stats.get_avg_total_time(), None);
+        assert_eq!(stats.get_avg_connect_time(), None);
+
+        stats.record(sample(true, 100), None);
+        stats.record(sample(true, 300), None);
+        assert_eq!(stats.get_avg_total_time(), Some(Duration::from_millis(200)));
+        assert_eq!(stats.get_avg_connect_time(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn last_error_is_kept() {
+        let mut stats = ProxyStats::default();
+        stats.record(sample(false, 100), Some(String::from("refused")));
+        stats.record(sample(true, 100), None);
+        assert_eq!(stats.get_last_error().map(|(_, e)| e.as_str()), Some("refused"));
+        stats.set_last_error(String::from("wrong status"));
+        assert_eq!(stats.get_last_error().map(|(_, e)| e.as_str()), Some("wrong status"));
+    }
+}
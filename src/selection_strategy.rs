@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::proxy_conn::ProxyConn;
+
+/* Decides which of the ready proxies is handed out next. The ready list is
+   locked while a strategy looks at it, strategies may lock the proxies in it
+   but must not take any other list lock. */
+pub trait SelectionStrategy: Send + Sync {
+    /* The name used for it in the configuration and the STRATEGY TLV */
+    fn name(&self) -> &'static str;
+
+    /* Index of the proxy to hand out next, None if there is none */
+    fn select(&self, ready: &VecDeque<Arc<Mutex<ProxyConn>>>) -> Option<usize>;
+}
+
+/* Hands out the proxy that has been ready the longest. Released proxies go
+   to the back of the ready list, so this rotates through all of them. */
+pub struct RoundRobin;
+
+impl SelectionStrategy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round_robin"
+    }
+
+    fn select(&self, ready: &VecDeque<Arc<Mutex<ProxyConn>>>) -> Option<usize> {
+        if ready.is_empty() { None } else { Some(0) }
+    }
+}
+
+pub struct Random;
+
+impl SelectionStrategy for Random {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn select(&self, ready: &VecDeque<Arc<Mutex<ProxyConn>>>) -> Option<usize> {
+        if ready.is_empty() { None } else { Some(fastrand::usize(..ready.len())) }
+    }
+}
+
+/* Hands out the proxy that has gone unused the longest, never used ones
+   first */
+pub struct LeastRecentlyUsed;
+
+impl SelectionStrategy for LeastRecentlyUsed {
+    fn name(&self) -> &'static str {
+        "least_recently_used"
+    }
+
+    fn select(&self, ready: &VecDeque<Arc<Mutex<ProxyConn>>>) -> Option<usize> {
+        ready.iter()
+            .enumerate()
+            .min_by_key(|(_, p)| p.lock().unwrap().get_last_used())
+            .map(|(i, _)| i)
+    }
+}
+
+/* Hands out the proxy with the lowest average transfer time */
+pub struct LowestLatency;
+
+impl SelectionStrategy for LowestLatency {
+    fn name(&self) -> &'static str {
+        "lowest_latency"
+    }
+
+    fn select(&self, ready: &VecDeque<Arc<Mutex<ProxyConn>>>) -> Option<usize> {
+        ready.iter()
+            .enumerate()
+            .min_by_key(|(_, p)| p.lock().unwrap().get_stats().get_avg_total_time().unwrap_or(Duration::MAX))
+            .map(|(i, _)| i)
+    }
+}
+
+/* Picks at random, each proxy weighted by the share of its recent transfers
+   that succeeded */
+pub struct WeightedSuccessRate;
+
+impl SelectionStrategy for WeightedSuccessRate {
+    fn name(&self) -> &'static str {
+        "success_rate"
+    }
+
+    fn select(&self, ready: &VecDeque<Arc<Mutex<ProxyConn>>>) -> Option<usize> {
+        let weights: Vec<f64> = ready.iter()
+            .map(|p| p.lock().unwrap().get_stats().get_success_rate())
+            .collect();
+        let mut pick = fastrand::f64() * weights.iter().sum::<f64>();
+        for (i, w) in weights.iter().enumerate() {
+            if pick < *w {
+                return Some(i);
+            }
+            pick -= w;
+        }
+        /* Rounding can leave pick just above the last weight */
+        weights.len().checked_sub(1)
+    }
+}
+
+static STRATEGIES: [&dyn SelectionStrategy; 5] = [
+    &RoundRobin,
+    &Random,
+    &LeastRecentlyUsed,
+    &LowestLatency,
+    &WeightedSuccessRate,
+];
+
+/* Look up a strategy by its name */
+pub fn strategy_by_name(name: &str) -> Result<&'static dyn SelectionStrategy, String> {
+    STRATEGIES.iter()
+        .find(|s| s.name() == name.trim())
+        .copied()
+        .ok_or_else(|| format!("Unknown selection strategy '{}'", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::proxy_conn::{ProxyConnMethod, ProxyConnProtocol};
+
+    fn proxy(port: u16) -> Arc<Mutex<ProxyConn>> {
+        Arc::new(Mutex::new(ProxyConn::new(port, ProxyConnProtocol::HTTP, String::from("127.0.0.1"), port, None, None)))
+    }
+
+    fn ready(proxies: &[&Arc<Mutex<ProxyConn>>]) -> VecDeque<Arc<Mutex<ProxyConn>>> {
+        proxies.iter().map(|p| Arc::clone(p)).collect()
+    }
+
+    /* A proxy with one successful transfer that took at least delay */
+    fn answered_after(delay: Duration) -> Arc<Mutex<ProxyConn>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let upstream = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            thread::sleep(delay);
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+        });
+        let proxy = proxy(port);
+        proxy.lock().unwrap().request(ProxyConnMethod::GET, "http://example.com/", &None, 5, None).unwrap();
+        upstream.join().unwrap();
+        proxy
+    }
+
+    /* A proxy whose recent transfers all failed, nothing listens on port 1 */
+    fn failing(transfers: usize) -> Arc<Mutex<ProxyConn>> {
+        let proxy = proxy(1);
+        for _ in 0..transfers {
+            assert!(proxy.lock().unwrap().request(ProxyConnMethod::GET, "http://example.com/", &None, 1, None).is_err());
+        }
+        proxy
+    }
+
+    #[test]
+    fn nothing_ready_selects_nothing() {
+        let empty = VecDeque::new();
+        for strategy in STRATEGIES {
+            assert_eq!(strategy.select(&empty), None, "{}", strategy.name());
+        }
+    }
+
+    #[test]
+    fn round_robin_takes_the_front() {
+        let (a, b) = (proxy(1), proxy(2));
+        assert_eq!(RoundRobin.select(&ready(&[&a, &b])), Some(0));
+    }
+
+    #[test]
+    fn random_stays_in_range() {
+        let (a, b, c) = (proxy(1), proxy(2), proxy(3));
+        let ready = ready(&[&a, &b, &c]);
+        for _ in 0..100 {
+            assert!(Random.select(&ready).unwrap() < ready.len());
+        }
+    }
+
+    #[test]
+    fn least_recently_used_prefers_never_used() {
+        let (a, b, c) = (proxy(1), proxy(2), proxy(3));
+        a.lock().unwrap().set_used();
+        thread::sleep(Duration::from_millis(2));
+        c.lock().unwrap().set_used();
+        assert_eq!(LeastRecentlyUsed.select(&ready(&[&a, &b, &c])), Some(1));
+
+        b.lock().unwrap().set_used();
+        assert_eq!(LeastRecentlyUsed.select(&ready(&[&c, &b, &a])), Some(2));
+    }
+
+    #[test]
+    fn lowest_latency_prefers_the_fastest() {
+        let fast = answered_after(Duration::ZERO);
+        let slow = answered_after(Duration::from_millis(300));
+        let unmeasured = proxy(1);
+        assert_eq!(LowestLatency.select(&ready(&[&unmeasured, &slow, &fast])), Some(2));
+        /* Having no successful transfers counts as slowest */
+        assert_eq!(LowestLatency.select(&ready(&[&unmeasured, &slow])), Some(1));
+    }
+
+    #[test]
+    fn success_rate_favours_working_proxies() {
+        let bad = failing(20);
+        let unknown = proxy(2);
+        let ready = ready(&[&bad, &unknown]);
+
+        /* Weighted 1/22 against 1/2, the unknown one wins most picks */
+        let mut picks = [0_usize; 2];
+        for _ in 0..1000 {
+            picks[WeightedSuccessRate.select(&ready).unwrap()] += 1;
+        }
+        assert!(picks[1] > picks[0] * 3, "{:?}", picks);
+        assert_eq!(WeightedSuccessRate.select(&VecDeque::from([bad])), Some(0));
+    }
+
+    #[test]
+    fn strategies_are_found_by_name() {
+        for strategy in STRATEGIES {
+            assert_eq!(strategy_by_name(strategy.name()).unwrap().name(), strategy.name());
+        }
+        assert_eq!(strategy_by_name(" lowest_latency ").unwrap().name(), "lowest_latency");
+        assert!(strategy_by_name("fastest").is_err());
+        assert!(strategy_by_name("").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::proxify_data::{ProxifyCommand, ProxifyStatus};
+use crate::daemon::ThreadSafeList;
+use crate::metrics::Histogram;
+use crate::proxy_conn::ProxyConn;
+
+/* Daemon wide counters, shared by all connections */
+pub struct DaemonCounters {
+    started: Instant,
+    /* Requests answered, by command and status */
+    requests: Mutex<HashMap<(ProxifyCommand, ProxifyStatus), u64>>,
+    prepare_attempts: AtomicU64,
+    prepare_failures: AtomicU64,
+    /* Client request durations by proxy id and protocol */
+    upstream_latency: Mutex<HashMap<(u16, String), Histogram>>,
+}
+
+impl DaemonCounters {
+    pub fn new() -> Self {
+        DaemonCounters {
+            started: Instant::now(),
+            requests: Mutex::new(HashMap::new()),
+            prepare_attempts: AtomicU64::new(0),
+            prepare_failures: AtomicU64::new(0),
+            upstream_latency: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get_uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn record_request(&self, command: ProxifyCommand, status: ProxifyStatus) {
+        *self.requests.lock().unwrap().entry((command, status)).or_default() += 1;
+    }
+
+    pub fn get_request_counts(&self) -> Vec<((ProxifyCommand, ProxifyStatus), u64)> {
+        self.requests.lock().unwrap().iter().map(|(k, v)| (*k, *v)).collect()
+    }
+
+    pub fn record_prepare(&self, success: bool) {
+        self.prepare_attempts.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.prepare_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /* Health checks run and how many of them failed */
+    pub fn get_prepare_counts(&self) -> (u64, u64) {
+        (self.prepare_attempts.load(Ordering::Relaxed), self.prepare_failures.load(Ordering::Relaxed))
+    }
+
+    pub fn record_upstream_latency(&self, proxy_id: u16, protocol: String, duration: Duration) {
+        self.upstream_latency.lock().unwrap()
+            .entry((proxy_id, protocol))
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /* The histograms are only lent out, they stay locked meanwhile */
+    pub fn with_upstream_latency(&self, f: impl FnOnce(&HashMap<(u16, String), Histogram>)) {
+        f(&self.upstream_latency.lock().unwrap());
+    }
+}
+
+impl Default for DaemonCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize)]
+pub struct PoolSizes {
+    pub not_ready: usize,
+    pub ready: usize,
+    pub in_use: usize,
+    pub quarantined: usize,
+}
+
+#[derive(Serialize)]
+pub struct RequestTotals {
+    pub total: u64,
+    pub by_command: BTreeMap<String, u64>,
+    pub by_status: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+pub struct RecentTransfers {
+    pub transfers: usize,
+    pub successes: usize,
+    /* Unlike the weight used for selection, None without any transfers */
+    pub success_rate: Option<f64>,
+    pub avg_connect_ms: Option<u128>,
+    pub avg_total_ms: Option<u128>,
+}
+
+#[derive(Serialize)]
+pub struct TotalTransfers {
+    pub successes: u64,
+    pub failures: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Serialize)]
+pub struct LastError {
+    pub message: String,
+    pub secs_ago: u64,
+}
+
+#[derive(Serialize)]
+pub struct ProxyReport {
+    pub id: u16,
+    pub address: String,
+    /* The pool the proxy is in */
+    pub state: &'static str,
+    pub consecutive_failures: u32,
+    pub failed_checks: u64,
+    /* Seconds left before the proxy is checked again after a failure */
+    pub backoff_secs: Option<u64>,
+    pub quarantined: bool,
+    pub disabled: bool,
+    pub last_used_secs_ago: Option<u64>,
+    pub recent: RecentTransfers,
+    pub totals: TotalTransfers,
+    pub last_error: Option<LastError>,
+}
+
+impl ProxyReport {
+    pub fn new(state: &'static str, p: &ProxyConn) -> Self {
+        let stats = p.get_stats();
+        let (transfers, successes) = stats.get_recent_counts();
+        let now = Instant::now();
+
+        ProxyReport {
+            id: p.get_id(),
+            address: p.get_address(),
+            state,
+            consecutive_failures: p.get_consecutive_failures(),
+            failed_checks: p.get_total_failures(),
+            backoff_secs: p.get_retry_after()
+                .filter(|t| *t > now)
+                .map(|t| (t - now).as_secs()),
+            quarantined: p.is_quarantined(),
+            disabled: p.is_disabled(),
+            last_used_secs_ago: p.get_last_used().map(|t| t.elapsed().as_secs()),
+            recent: RecentTransfers {
+                transfers,
+                successes,
+                success_rate: if transfers > 0 { Some(successes as f64 / transfers as f64) } else { None },
+                avg_connect_ms: stats.get_avg_connect_time().map(|d| d.as_millis()),
+                avg_total_ms: stats.get_avg_total_time().map(|d| d.as_millis()),
+            },
+            totals: TotalTransfers {
+                successes: stats.get_total_successes(),
+                failures: stats.get_total_failures(),
+                bytes_sent: stats.get_total_bytes_sent(),
+                bytes_received: stats.get_total_bytes_received(),
+            },
+            last_error: stats.get_last_error().map(|(at, message)| LastError {
+                message: message.clone(),
+                secs_ago: at.elapsed().as_secs(),
+            }),
+        }
+    }
+}
+
+/* What the STATS command answers with */
+#[derive(Serialize)]
+pub struct StatsReport {
+    pub uptime_secs: u64,
+    pub active_connections: usize,
+    pub pools: PoolSizes,
+    pub requests: RequestTotals,
+    pub proxies: Vec<ProxyReport>,
+}
+
+impl StatsReport {
+    /* Only one list is locked at a time, its proxies are locked after it
+       is released */
+    pub fn new(counters: &DaemonCounters,
+               active_connections: usize,
+               notready_proxies: &ThreadSafeList,
+               ready_proxies: &ThreadSafeList,
+               inuse_proxies: &ThreadSafeList,
+               quarantined_proxies: &ThreadSafeList) -> Self {
+        let mut proxies: Vec<ProxyReport> = Vec::new();
+        let mut report_pool = |state: &'static str, list: &ThreadSafeList| -> usize {
+            let list: Vec<_> = list.lock().unwrap().iter().cloned().collect();
+            for proxy in &list {
+                proxies.push(ProxyReport::new(state, &proxy.lock().unwrap()));
+            }
+            list.len()
+        };
+        let pools = PoolSizes {
+            not_ready: report_pool("not_ready", notready_proxies),
+            ready: report_pool("ready", ready_proxies),
+            in_use: report_pool("in_use", inuse_proxies),
+            quarantined: report_pool("quarantined", quarantined_proxies),
+        };
+        proxies.sort_by_key(|p| p.id);
+
+        let mut requests = RequestTotals {
+            total: 0,
+            by_command: BTreeMap::new(),
+            by_status: BTreeMap::new(),
+        };
+        for ((command, status), count) in counters.get_request_counts() {
+            requests.total += count;
+            *requests.by_command.entry(format!("{:?}", command)).or_default() += count;
+            *requests.by_status.entry(format!("{:?}", status)).or_default() += count;
+        }
+
+        StatsReport {
+            uptime_secs: counters.get_uptime().as_secs(),
+            active_connections,
+            pools,
+            requests,
+            proxies,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use curl::easy::Easy2;
+use curl::multi::{Easy2Handle, Multi, MultiWaker};
+use tokio::sync::oneshot;
+
+use crate::common::VERBOSITY;
+use crate::common::verbose_print::VerbosityLevel;
+use crate::{Error, Spam};
+use crate::proxy_conn::{ProxyConnCollector, ProxyConnError};
+
+/* A finished transfer: the handle, which is gone if the transfer could not
+   be started, and how the transfer went */
+pub type TransferResult = (Option<Easy2<ProxyConnCollector>>, Result<(), ProxyConnError>);
+
+type TransferJob = (Easy2<ProxyConnCollector>, oneshot::Sender<TransferResult>);
+
+/* Drives every transfer handed to it on one cURL multi handle from a single
+   thread, so hundreds of requests and health checks can be in flight without
+   a thread each. Callers await the result of their transfer. */
+pub struct TransferDriver {
+    jobs: mpsc::Sender<TransferJob>,
+    waker: MultiWaker,
+    stopping: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TransferDriver {
+    /* Upper bound on how long the thread sleeps without socket activity */
+    const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn new() -> std::io::Result<Self> {
+        let (jobs, jobs_rx) = mpsc::channel();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stopping_clone = stopping.clone();
+
+        /* The multi handle can not move between threads, it is created by
+           the driver thread which hands back a waker for it */
+        let (waker_tx, waker_rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(String::from("transfer-driver"))
+            .spawn(move || {
+                let multi = Multi::new();
+                if waker_tx.send(multi.waker()).is_ok() {
+                    Self::run(multi, jobs_rx, stopping_clone);
+                }
+            })?;
+        let waker = waker_rx.recv()
+            .map_err(|_| std::io::Error::other("The transfer driver thread did not start"))?;
+
+        Ok(TransferDriver {
+            jobs,
+            waker,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+
+    /* Perform a transfer that has been set up on the handle. The handle is
+       handed back with the result so it can be reused. */
+    pub async fn perform(&self, handle: Easy2<ProxyConnCollector>) -> TransferResult {
+        let stopped = || ProxyConnError::Failed(String::from("The transfer driver has stopped"));
+
+        let (done, result) = oneshot::channel();
+        if let Err(mpsc::SendError((handle, _))) = self.jobs.send((handle, done)) {
+            return (Some(handle), Err(stopped()));
+        }
+        if let Err(e) = self.waker.wakeup() {
+            Error!("Failed to wake up the transfer driver: {}", e);
+        }
+        match result.await {
+            Ok(r) => r,
+            Err(_) => (None, Err(stopped())),
+        }
+    }
+
+    fn run(multi: Multi, jobs: mpsc::Receiver<TransferJob>, stopping: Arc<AtomicBool>) {
+        let mut transfers: HashMap<usize, (Easy2Handle<ProxyConnCollector>, oneshot::Sender<TransferResult>)> =
+            HashMap::new();
+        let mut next_token = 0_usize;
+
+        Spam!("Transfer driver is running");
+        while !stopping.load(Ordering::Relaxed) {
+            /* Pick up the transfers submitted since the last round */
+            while let Ok((handle, done)) = jobs.try_recv() {
+                let mut handle = match multi.add2(handle) {
+                    Ok(h) => h,
+                    Err(e) => {
+                        let _ = done.send((None, Err(ProxyConnError::Failed(format!("Failed to start the transfer: {}", e)))));
+                        continue;
+                    },
+                };
+                let token = next_token;
+                next_token = next_token.wrapping_add(1);
+                if let Err(e) = handle.set_token(token) {
+                    let handle = multi.remove2(handle).ok();
+                    let _ = done.send((handle, Err(ProxyConnError::from(e))));
+                    continue;
+                }
+                transfers.insert(token, (handle, done));
+            }
+
+            if let Err(e) = multi.perform() {
+                Error!("Failed to perform transfers: {}", e);
+            }
+
+            let mut finished: Vec<(usize, Result<(), curl::Error>)> = Vec::new();
+            multi.messages(|msg| {
+                let token = match msg.token() {
+                    Ok(t) => t,
+                    Err(_) => return,
+                };
+                if let Some((handle, _)) = transfers.get(&token) {
+                    if let Some(result) = msg.result_for2(handle) {
+                        finished.push((token, result));
+                    }
+                }
+            });
+            for (token, result) in finished {
+                let (handle, done) = transfers.remove(&token).unwrap();
+                let handle = match multi.remove2(handle) {
+                    Ok(h) => Some(h),
+                    Err(e) => {
+                        Error!("Failed to remove a finished transfer: {}", e);
+                        None
+                    },
+                };
+                let _ = done.send((handle, result.map_err(ProxyConnError::from)));
+            }
+
+            if let Err(e) = multi.poll(&mut [], Self::POLL_TIMEOUT) {
+                Error!("Failed to wait for transfers: {}", e);
+            }
+        }
+
+        /* Dropping the senders tells whoever waits for the remaining
+           transfers that they will not finish */
+        for (_, (handle, _)) in transfers.drain() {
+            let _ = multi.remove2(handle);
+        }
+        Spam!("Transfer driver is exiting");
+    }
+}
+
+impl Drop for TransferDriver {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        let _ = self.waker.wakeup();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use proxify::proxify_client::ProxifyClient;
+use proxify::proxify_data::ProxifyStatus;
+use serde_json::Value;
+
+/* A running daemon, killed when dropped */
+struct Daemon {
+    child: Child,
+    proxies_file: PathBuf,
+    addr: String,
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.proxies_file);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/* Start the daemon on the given proxies, health checked against
+   http://upstream.test/check, with more settings appended to the config */
+fn start_daemon(name: &str, proxy_ports: &[u16], config: &str) -> Daemon {
+    let proxies_file = std::env::temp_dir().join(format!("proxify-{}-{}.txt", name, std::process::id()));
+    let proxies: Vec<String> = proxy_ports.iter().map(|p| format!("http://127.0.0.1:{}", p)).collect();
+    fs::write(&proxies_file, proxies.join("\n")).unwrap();
+
+    let port = free_port();
+    let config = format!("bind_port={};proxies_file={};check_url=http://upstream.test/check;{}",
+                         port, proxies_file.display(), config);
+    let child = Command::new(env!("CARGO_BIN_EXE_proxify"))
+        .args(["--config", &config])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let daemon = Daemon { child, proxies_file, addr: format!("127.0.0.1:{}", port) };
+
+    /* Up once every proxy has passed its health check */
+    let started = Instant::now();
+    let mut client = ProxifyClient::new(&daemon.addr);
+    loop {
+        if let Ok(r) = client.stats() {
+            let stats: Value = serde_json::from_slice(&r.get_body()).unwrap();
+            if stats["pools"]["ready"].as_u64() == Some(proxy_ports.len() as u64) {
+                break;
+            }
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "The daemon did not get ready");
+        thread::sleep(Duration::from_millis(100));
+    }
+    daemon
+}
+
+/* A stand-in for an HTTP proxy that answers every request with 200, except
+   those for /stall which it never answers. /set-cookie sets a cookie and
+   every body is the Cookie header that came with the request. */
+fn fake_proxy() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || serve_proxy_connection(stream));
+        }
+    });
+    port
+}
+
+fn serve_proxy_connection(mut stream: TcpStream) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut cookie = String::new();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("cookie") {
+                    cookie = value.trim().to_string();
+                }
+            }
+            line.clear();
+        }
+        if request_line.contains("/stall") {
+            thread::sleep(Duration::from_secs(5));
+            return;
+        }
+        let set_cookie = if request_line.contains("/set-cookie") { "Set-Cookie: visit=1; Path=/\r\n" } else { "" };
+        let reply = format!("HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n{}", set_cookie, cookie.len(), cookie);
+        if stream.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/* The number of proxies in the in_use pool */
+fn proxies_in_use(daemon: &Daemon) -> u64 {
+    let mut client = ProxifyClient::new(&daemon.addr);
+    let stats: Value = serde_json::from_slice(&client.stats().unwrap().get_body()).unwrap();
+    stats["pools"]["in_use"].as_u64().unwrap()
+}
+
+/* Poll until the daemon has got all its proxies back */
+fn wait_for_release(daemon: &Daemon, within: Duration) {
+    let started = Instant::now();
+    while proxies_in_use(daemon) > 0 {
+        assert!(started.elapsed() < within, "The proxy was not released");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn session_keeps_its_proxy_and_cookies() {
+    let daemon = start_daemon("session", &[fake_proxy(), fake_proxy()], "");
+    let mut client = ProxifyClient::new(&daemon.addr);
+    client.set_session(1);
+
+    let first = client.get("http://upstream.test/set-cookie", &[]).unwrap();
+    assert_eq!(first.status, ProxifyStatus::OK);
+    let second = client.get("http://upstream.test/echo", &[]).unwrap();
+    assert_eq!(second.status, ProxifyStatus::OK);
+    assert_eq!(second.proxy_id, first.proxy_id);
+    assert_eq!(second.get_body(), b"visit=1");
+
+    /* Without a session the proxies rotate and no cookies are sent */
+    client.set_session(0);
+    let other = client.get("http://upstream.test/echo", &[]).unwrap();
+    assert_eq!(other.status, ProxifyStatus::OK);
+    assert_ne!(other.proxy_id, first.proxy_id);
+    assert!(other.get_body().is_empty());
+}
+
+#[test]
+fn end_session_releases_the_proxy() {
+    let daemon = start_daemon("end-session", &[fake_proxy(), fake_proxy()], "");
+    let mut client = ProxifyClient::new(&daemon.addr);
+    client.set_session(1);
+
+    let response = client.get("http://upstream.test/echo", &[]).unwrap();
+    assert_eq!(proxies_in_use(&daemon), 1);
+    let ended = client.end_session().unwrap();
+    assert_eq!(ended.status, ProxifyStatus::OK);
+    assert_eq!(ended.proxy_id, response.proxy_id);
+    assert_eq!(proxies_in_use(&daemon), 0);
+
+    /* A new session starts without the old cookies */
+    client.get("http://upstream.test/set-cookie", &[]).unwrap();
+    client.end_session().unwrap();
+    let fresh = client.get("http://upstream.test/echo", &[]).unwrap();
+    assert!(fresh.get_body().is_empty());
+}
+
+#[test]
+fn idle_session_expires() {
+    let daemon = start_daemon("expire", &[fake_proxy(), fake_proxy()], "session_timeout=1");
+    let mut client = ProxifyClient::new(&daemon.addr);
+    client.set_session(1);
+
+    client.get("http://upstream.test/echo", &[]).unwrap();
+    assert_eq!(proxies_in_use(&daemon), 1);
+    /* The connection stays open, only the session goes idle */
+    wait_for_release(&daemon, Duration::from_secs(5));
+    assert!(client.is_connected());
+}
+
+#[test]
+fn disconnecting_ends_the_session() {
+    let daemon = start_daemon("disconnect", &[fake_proxy(), fake_proxy()], "");
+    let mut client = ProxifyClient::new(&daemon.addr);
+    client.set_session(1);
+
+    client.get("http://upstream.test/echo", &[]).unwrap();
+    assert_eq!(proxies_in_use(&daemon), 1);
+    drop(client);
+    wait_for_release(&daemon, Duration::from_secs(5));
+}
+
+#[test]
+fn stalled_upstream_is_an_upstream_timeout() {
+    let proxy = fake_proxy();
+    let daemon = start_daemon("stalled", &[proxy, proxy], "request_timeout=1");
+
+    /* The client's own timeout is left at its default */
+    let mut client = ProxifyClient::new(&daemon.addr);
+    let started = Instant::now();
+    let response = client.get("http://upstream.test/stall", &[]).unwrap();
+    assert_eq!(response.status, ProxifyStatus::UPSTREAM_TIMEOUT);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(client.is_connected());
+}
@@ -0,0 +1,113 @@
+use proptest::prelude::*;
+
+use proxify::proxify_data::{ProxifyCommand, ProxifyData, ProxifyDataType,
+                            ProxifyResponse, ProxifyStatus};
+
+fn any_command() -> impl Strategy<Value = ProxifyCommand> {
+    prop_oneof![
+        Just(ProxifyCommand::REQUEST_GET),
+        Just(ProxifyCommand::REQUEST_POST),
+        Just(ProxifyCommand::END_SESSION),
+        Just(ProxifyCommand::REQUEST),
+        Just(ProxifyCommand::STATS),
+    ]
+}
+
+fn any_status() -> impl Strategy<Value = ProxifyStatus> {
+    prop_oneof![
+        Just(ProxifyStatus::OK),
+        Just(ProxifyStatus::NO_PROXY_READY),
+        Just(ProxifyStatus::UPSTREAM_TIMEOUT),
+        Just(ProxifyStatus::UPSTREAM_ERROR),
+        Just(ProxifyStatus::BAD_REQUEST),
+        Just(ProxifyStatus::BUSY),
+        Just(ProxifyStatus::SHUTTING_DOWN),
+    ]
+}
+
+fn any_tlvs() -> impl Strategy<Value = Vec<(ProxifyDataType, u32, Vec<u8>)>> {
+    let tlv_type = prop_oneof![
+        Just(ProxifyDataType::URL),
+        Just(ProxifyDataType::HEADER),
+        Just(ProxifyDataType::DATA),
+        Just(ProxifyDataType::STATUS),
+        Just(ProxifyDataType::ERROR),
+        Just(ProxifyDataType::METHOD),
+        Just(ProxifyDataType::STRATEGY),
+    ];
+    prop::collection::vec(
+        (tlv_type, prop::collection::vec(any::<u8>(), 0..600))
+            .prop_map(|(t, v)| (t, v.len() as u32, v)),
+        0..8)
+}
+
+proptest! {
+    #[test]
+    fn data_encode_then_decode(session in any::<u8>(),
+                               command in any_command(),
+                               request_id in any::<u32>(),
+                               tlvs in any_tlvs()) {
+        let data = ProxifyData { session, command, request_id, data: tlvs };
+        let decoded = ProxifyData::unmarshal_bytes(&data.marshal_bytes()).unwrap();
+        prop_assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn data_decode_then_encode(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        if let Ok(data) = ProxifyData::unmarshal_bytes(&bytes) {
+            prop_assert_eq!(data.marshal_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn response_encode_then_decode(session in any::<u8>(),
+                                   status in any_status(),
+                                   request_id in any::<u32>(),
+                                   proxy_id in prop::option::of(0_u16..u16::MAX),
+                                   tlvs in any_tlvs()) {
+        let response = ProxifyResponse { session, status, request_id, proxy_id, data: tlvs };
+        let bytes = response.marshal_bytes();
+        prop_assert_eq!(response.marshalled_len(), bytes.len());
+        let decoded = ProxifyResponse::unmarshal_bytes(&bytes).unwrap();
+        prop_assert_eq!(decoded, response);
+    }
+
+    #[test]
+    fn response_decode_then_encode(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        if let Ok(response) = ProxifyResponse::unmarshal_bytes(&bytes) {
+            prop_assert_eq!(response.marshal_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn builder_round_trip(session in any::<u8>(),
+                          request_id in any::<u32>(),
+                          url in "[a-z:/.]{1,300}",
+                          headers in prop::collection::vec("[A-Za-z-]+: [ -~]*", 0..5),
+                          body in prop::collection::vec(any::<u8>(), 0..2000)) {
+        let mut builder = ProxifyData::builder(ProxifyCommand::REQUEST)
+            .session(session)
+            .request_id(request_id)
+            .url(&url)
+            .method("PUT");
+        for h in &headers {
+            builder = builder.header(h);
+        }
+        let built = builder.body(&body).build().unwrap();
+
+        let decoded = ProxifyData::unmarshal_bytes(&built.marshal_bytes()).unwrap();
+        prop_assert_eq!(decoded.session, session);
+        prop_assert_eq!(decoded.request_id, request_id);
+        prop_assert_eq!(decoded.get_url(), Some(url));
+        prop_assert_eq!(decoded.get_method(), Some(String::from("PUT")));
+        prop_assert_eq!(decoded.get_headers(), headers);
+        prop_assert_eq!(decoded.get_body(), body);
+    }
+}
+
+#[test]
+fn builder_requires_url_and_method() {
+    assert!(ProxifyData::builder(ProxifyCommand::REQUEST_GET).build().is_err());
+    assert!(ProxifyData::builder(ProxifyCommand::REQUEST).url("http://a").build().is_err());
+    assert!(ProxifyData::builder(ProxifyCommand::END_SESSION).build().is_ok());
+}
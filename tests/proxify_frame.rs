@@ -0,0 +1,72 @@
+use proxify::proxify_frame::{FrameReader, FRAME_HEADER_LEN, MAGIC_BYTES, MAX_FRAME_LEN,
+                             PROTOCOL_VERSION, frame_bytes};
+
+#[test]
+fn partial_frame_waits_for_the_rest() {
+    let bytes = frame_bytes(b"hello");
+    let mut frames = FrameReader::new();
+
+    /* Byte by byte, the header included */
+    for b in &bytes[..bytes.len() - 1] {
+        frames.push(std::slice::from_ref(b));
+        assert_eq!(frames.next_frame(), Ok(None));
+    }
+    frames.push(&bytes[bytes.len() - 1..]);
+    assert_eq!(frames.next_frame(), Ok(Some(b"hello".to_vec())));
+    assert_eq!(frames.next_frame(), Ok(None));
+}
+
+#[test]
+fn coalesced_frames_come_out_one_by_one() {
+    let mut bytes = frame_bytes(b"one");
+    bytes.extend(frame_bytes(b""));
+    bytes.extend(frame_bytes(b"three"));
+    /* And the start of a fourth one */
+    let fourth = frame_bytes(b"four");
+    bytes.extend_from_slice(&fourth[..FRAME_HEADER_LEN + 1]);
+
+    let mut frames = FrameReader::new();
+    frames.push(&bytes);
+    assert_eq!(frames.next_frame(), Ok(Some(b"one".to_vec())));
+    assert_eq!(frames.next_frame(), Ok(Some(Vec::new())));
+    assert_eq!(frames.next_frame(), Ok(Some(b"three".to_vec())));
+    assert_eq!(frames.next_frame(), Ok(None));
+
+    frames.push(&fourth[FRAME_HEADER_LEN + 1..]);
+    assert_eq!(frames.next_frame(), Ok(Some(b"four".to_vec())));
+}
+
+#[test]
+fn handshake_and_frame_in_one_read() {
+    let mut bytes = MAGIC_BYTES.to_vec();
+    bytes.extend(frame_bytes(b"payload"));
+
+    let mut frames = FrameReader::new();
+    frames.push(&bytes[..2]);
+    assert_eq!(frames.take_bytes(MAGIC_BYTES.len()), None);
+    frames.push(&bytes[2..]);
+    assert_eq!(frames.take_bytes(MAGIC_BYTES.len()), Some(MAGIC_BYTES.to_vec()));
+    assert_eq!(frames.next_frame(), Ok(Some(b"payload".to_vec())));
+}
+
+#[test]
+fn invalid_headers_are_refused() {
+    let mut bad_magic = frame_bytes(b"x");
+    bad_magic[0] = 0;
+    let mut frames = FrameReader::new();
+    frames.push(&bad_magic);
+    assert!(frames.next_frame().is_err());
+
+    let mut old_version = frame_bytes(b"x");
+    old_version[2] = PROTOCOL_VERSION - 1;
+    let mut frames = FrameReader::new();
+    frames.push(&old_version);
+    assert!(frames.next_frame().is_err());
+
+    /* Refused from the header alone, before the payload arrives */
+    let mut too_large = frame_bytes(b"");
+    too_large[3..7].copy_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+    let mut frames = FrameReader::new();
+    frames.push(&too_large);
+    assert!(frames.next_frame().is_err());
+}
@@ -0,0 +1,205 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use proxify::proxy_conn::{HealthCheck, MAX_RESPONSE_LEN, ProxyConn, ProxyConnError, ProxyConnMethod,
+                          ProxyConnProtocol, ProxyConnResponse};
+
+/* Nothing listens on port 1, setting up a request never gets that far */
+fn unreachable_proxy() -> ProxyConn {
+    ProxyConn::new(0, ProxyConnProtocol::HTTP, String::from("127.0.0.1"), 1, None, None)
+}
+
+/* A stand-in for an HTTP proxy that answers a single request with 200. The
+   thread returns the request line and the body it received. */
+fn fake_proxy() -> (ProxyConn, thread::JoinHandle<(String, Vec<u8>)>) {
+    fake_proxy_replying(b"ok".to_vec())
+}
+
+fn fake_proxy_replying(reply: Vec<u8>) -> (ProxyConn, thread::JoinHandle<(String, Vec<u8>)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_length = 0_usize;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim_end().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0_u8; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        /* The client may hang up before taking it all */
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", reply.len());
+        let _ = stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(&reply));
+        (request_line.trim_end().to_string(), body)
+    });
+    let proxy = ProxyConn::new(0, ProxyConnProtocol::HTTP, String::from("127.0.0.1"), port, None, None);
+    (proxy, handle)
+}
+
+#[test]
+fn header_with_nul_byte_is_an_error() {
+    let mut proxy = unreachable_proxy();
+    let headers = Some(vec![String::from("X-A: a\0b")]);
+    let result = proxy.request(ProxyConnMethod::GET, "http://127.0.0.1/", &headers, 1, None);
+    assert!(matches!(result, Err(ProxyConnError::Request(_))));
+
+    /* The handle is kept and still usable */
+    let result = proxy.request(ProxyConnMethod::GET, "http://127.0.0.1/", &headers, 1, None);
+    assert!(matches!(result, Err(ProxyConnError::Request(_))));
+}
+
+#[test]
+fn get_with_body_stays_a_get() {
+    let (mut proxy, upstream) = fake_proxy();
+    let resp = proxy.request(ProxyConnMethod::GET, "http://example.com/", &None, 5, Some(b"abc")).unwrap();
+    assert_eq!(resp.status_code, 200);
+    let (request_line, body) = upstream.join().unwrap();
+    assert!(request_line.starts_with("GET "), "{}", request_line);
+    assert_eq!(body, b"abc");
+}
+
+#[test]
+fn post_without_body_stays_a_post() {
+    let (mut proxy, upstream) = fake_proxy();
+    proxy.request(ProxyConnMethod::POST, "http://example.com/", &None, 5, None).unwrap();
+    let (request_line, body) = upstream.join().unwrap();
+    assert!(request_line.starts_with("POST "), "{}", request_line);
+    assert!(body.is_empty());
+}
+
+#[test]
+fn post_health_check_is_sent_as_post() {
+    let (mut proxy, upstream) = fake_proxy();
+    let check = HealthCheck {
+        url: String::from("http://example.com/check"),
+        method: ProxyConnMethod::POST,
+        ..HealthCheck::default()
+    };
+    assert_eq!(proxy.prepare(&check), Ok(true));
+    let (request_line, _) = upstream.join().unwrap();
+    assert!(request_line.starts_with("POST "), "{}", request_line);
+}
+
+#[test]
+fn head_drops_the_body() {
+    let (mut proxy, upstream) = fake_proxy();
+    proxy.request(ProxyConnMethod::HEAD, "http://example.com/", &None, 5, Some(b"abc")).unwrap();
+    let (request_line, body) = upstream.join().unwrap();
+    assert!(request_line.starts_with("HEAD "), "{}", request_line);
+    assert!(body.is_empty());
+}
+
+#[test]
+fn bad_url_is_not_the_proxys_fault() {
+    let mut proxy = unreachable_proxy();
+    let result = proxy.request(ProxyConnMethod::GET, "nosuchscheme://example.com/", &None, 1, None);
+    match result {
+        Err(e @ ProxyConnError::Request(_)) => assert!(!e.is_proxy_fault()),
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("The request succeeded"),
+    }
+}
+
+#[test]
+fn unreachable_proxy_is_the_proxys_fault() {
+    let mut proxy = unreachable_proxy();
+    let result = proxy.request(ProxyConnMethod::GET, "http://example.com/", &None, 1, None);
+    match result {
+        Err(e @ ProxyConnError::Proxy(_)) => assert!(e.is_proxy_fault()),
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("The request succeeded"),
+    }
+}
+
+#[test]
+fn response_over_the_limit_is_an_error() {
+    let (mut proxy, upstream) = fake_proxy_replying(vec![b'x'; MAX_RESPONSE_LEN + 1]);
+    let result = proxy.request(ProxyConnMethod::GET, "http://example.com/", &None, 5, None);
+    match result {
+        Err(e @ ProxyConnError::Failed(_)) => assert!(e.to_string().contains("larger than"), "{}", e),
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("The request succeeded"),
+    }
+    upstream.join().unwrap();
+}
+
+#[test]
+fn stalled_upstream_times_out() {
+    /* Accepts the connection and never answers */
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let stalled = thread::spawn(move || {
+        let (_stream, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_secs(3));
+    });
+
+    let mut proxy = ProxyConn::new(0, ProxyConnProtocol::HTTP, String::from("127.0.0.1"), port, None, None);
+    let started = Instant::now();
+    let result = proxy.request(ProxyConnMethod::GET, "http://example.com/", &None, 1, None);
+    assert!(matches!(result, Err(ProxyConnError::Timeout(_))), "{:?}", result.err());
+    assert!(started.elapsed() < Duration::from_secs(3));
+    stalled.join().unwrap();
+}
+
+fn response(status_code: u32, body: &[u8]) -> ProxyConnResponse {
+    ProxyConnResponse { status_code, headers: Vec::new(), body: body.to_vec() }
+}
+
+#[test]
+fn health_check_accepts_anything_by_default() {
+    let check = HealthCheck::default();
+    assert_eq!(check.verify(&response(200, b"")), Ok(()));
+    assert_eq!(check.verify(&response(503, b"down")), Ok(()));
+}
+
+#[test]
+fn health_check_status_ranges_are_inclusive() {
+    let check = HealthCheck { expected_status: vec![(200, 200), (300, 399)], ..HealthCheck::default() };
+    for code in [200, 300, 350, 399] {
+        assert_eq!(check.verify(&response(code, b"")), Ok(()), "{}", code);
+    }
+    for code in [199, 201, 299, 400] {
+        assert!(check.verify(&response(code, b"")).is_err(), "{}", code);
+    }
+}
+
+#[test]
+fn health_check_body_must_contain() {
+    let check = HealthCheck { body_contains: Some(String::from("pong")), ..HealthCheck::default() };
+    assert_eq!(check.verify(&response(200, b"ping pong")), Ok(()));
+    assert_eq!(check.verify(&response(200, b"pong")), Ok(()));
+    assert!(check.verify(&response(200, b"pon")).is_err());
+    assert!(check.verify(&response(200, b"")).is_err());
+
+    /* An empty needle is always found */
+    let check = HealthCheck { body_contains: Some(String::new()), ..HealthCheck::default() };
+    assert_eq!(check.verify(&response(200, b"")), Ok(()));
+}
+
+#[test]
+fn health_check_status_is_checked_before_the_body() {
+    let check = HealthCheck {
+        expected_status: vec![(200, 299)],
+        body_contains: Some(String::from("pong")),
+        ..HealthCheck::default()
+    };
+    let err = check.verify(&response(500, b"pong")).unwrap_err();
+    assert!(err.contains("500"), "{}", err);
+    let err = check.verify(&response(200, b"")).unwrap_err();
+    assert!(err.contains("pong"), "{}", err);
+}